@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{parser::{DataType, OpCode, OpCodeIdentity}, symbols::SymbolTable};
+
+///How control reaches the target of an edge
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum EdgeKind {
+    Fallthrough,    //Next instruction, including the return site of a call
+    Jump,           //Unconditional jump
+    Call,           //Entry of a called subroutine
+    SkipTaken,      //Skip instruction condition held
+    SkipNotTaken,   //Skip instruction condition failed
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Edge {
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+///Straight-line run of instructions, `end` is exclusive
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub end: u16,
+    pub successors: Vec<Edge>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub callers: Vec<u16>,
+    pub blocks: BTreeSet<u16>,
+    pub returns: bool,
+}
+
+pub fn is_skip(id: OpCodeIdentity) -> bool {
+    matches!(
+        id,
+        OpCodeIdentity::SkipEqRC
+            | OpCodeIdentity::SkipNqRC
+            | OpCodeIdentity::SkipEqRR
+            | OpCodeIdentity::SkipNqRR
+            | OpCodeIdentity::SkipKeyPressedR
+            | OpCodeIdentity::SkipNKeyPressedR
+    )
+}
+
+///Statically known successors of the instruction at `addr`.
+///
+///`RetSub` and `JumpAddrCR` have none since their targets depend on runtime state.
+pub fn successors(oc: &OpCode, addr: u16) -> Vec<Edge> {
+    let next = addr.wrapping_add(1);
+    let target = match oc.get_data() {
+        DataType::NNN { address } => address,
+        _ => 0,
+    };
+    match oc.oc_id {
+        OpCodeIdentity::JumpAddr => vec![Edge { target, kind: EdgeKind::Jump }],
+        OpCodeIdentity::CallSub => vec![
+            Edge { target, kind: EdgeKind::Call },
+            Edge { target: next, kind: EdgeKind::Fallthrough },
+        ],
+        OpCodeIdentity::RetSub | OpCodeIdentity::JumpAddrCR => Vec::new(),
+        id if is_skip(id) => vec![
+            Edge { target: next, kind: EdgeKind::SkipNotTaken },
+            Edge { target: next.wrapping_add(1), kind: EdgeKind::SkipTaken },
+        ],
+        _ => vec![Edge { target: next, kind: EdgeKind::Fallthrough }],
+    }
+}
+
+fn ends_block(oc: &OpCode) -> bool {
+    matches!(
+        oc.oc_id,
+        OpCodeIdentity::JumpAddr | OpCodeIdentity::CallSub | OpCodeIdentity::RetSub | OpCodeIdentity::JumpAddrCR
+    ) || is_skip(oc.oc_id)
+}
+
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    ///Addresses of `JumpAddrCR` instructions whose target is only known at runtime
+    pub computed_jumps: Vec<u16>,
+    reachable: BTreeSet<u16>,
+}
+
+impl ControlFlowGraph {
+    pub fn build(program: &[OpCode]) -> Self {
+        let len = program.len() as u16;
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for (addr, oc) in program.iter().enumerate() {
+            let addr = addr as u16;
+            if !ends_block(oc) {
+                continue;
+            }
+            if addr + 1 < len {
+                leaders.insert(addr + 1);
+            }
+            for edge in successors(oc, addr) {
+                if edge.target < len {
+                    leaders.insert(edge.target);
+                }
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut computed_jumps = Vec::new();
+        for &start in &leaders {
+            let mut end = start;
+            let mut succ = Vec::new();
+            while end < len {
+                let oc = &program[end as usize];
+                end += 1;
+                if oc.oc_id == OpCodeIdentity::JumpAddrCR {
+                    computed_jumps.push(end - 1);
+                }
+                if ends_block(oc) {
+                    succ = successors(oc, end - 1);
+                    break;
+                }
+                if leaders.contains(&end) {
+                    succ = vec![Edge { target: end, kind: EdgeKind::Fallthrough }];
+                    break;
+                }
+            }
+            blocks.insert(start, BasicBlock { start, end, successors: succ });
+        }
+
+        let mut cfg = Self {
+            blocks,
+            subroutines: BTreeMap::new(),
+            computed_jumps,
+            reachable: BTreeSet::new(),
+        };
+        cfg.reachable = cfg.walk(0, true);
+        cfg.find_subroutines(program);
+        cfg
+    }
+
+    ///Blocks reachable from `entry`, optionally descending into called subroutines
+    pub fn walk(&self, entry: u16, follow_calls: bool) -> BTreeSet<u16> {
+        let mut seen = BTreeSet::new();
+        let mut queue = VecDeque::from([entry]);
+        while let Some(start) = queue.pop_front() {
+            let Some(block) = self.blocks.get(&start) else {
+                continue;
+            };
+            if !seen.insert(start) {
+                continue;
+            }
+            for edge in &block.successors {
+                if edge.kind != EdgeKind::Call || follow_calls {
+                    queue.push_back(edge.target);
+                }
+            }
+        }
+        seen
+    }
+
+    fn find_subroutines(&mut self, program: &[OpCode]) {
+        let mut callers: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+        for block in self.blocks.values() {
+            for edge in block.successors.iter().filter(|e| e.kind == EdgeKind::Call) {
+                callers.entry(edge.target).or_default().push(block.end - 1);
+            }
+        }
+        for (entry, callers) in callers {
+            let blocks = self.walk(entry, false);
+            let returns = blocks.iter().any(|start| {
+                let block = &self.blocks[start];
+                program[(block.end - 1) as usize].oc_id == OpCodeIdentity::RetSub
+            });
+            self.subroutines.insert(entry, Subroutine { entry, callers, blocks, returns });
+        }
+    }
+
+    pub fn block_containing(&self, addr: u16) -> Option<&BasicBlock> {
+        self.blocks.range(..=addr).next_back().map(|(_, b)| b).filter(|b| addr < b.end)
+    }
+
+    pub fn is_reachable(&self, addr: u16) -> bool {
+        self.block_containing(addr).is_some_and(|b| self.reachable.contains(&b.start))
+    }
+
+    pub fn unreachable_blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values().filter(|b| !self.reachable.contains(&b.start))
+    }
+
+    ///Renders the graph in Graphviz DOT format
+    pub fn to_dot(&self, program: &[OpCode], symbols: Option<&SymbolTable>) -> String {
+        let name = |addr: u16| match symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{:#05X}", addr),
+        };
+        let mut out = String::from("digraph program {\n    node [shape=box, fontname=monospace];\n");
+        for block in self.blocks.values() {
+            let mut label = format!("{}\\l", name(block.start));
+            for addr in block.start..block.end {
+                let oc = &program[addr as usize];
+                label.push_str(&format!("{:#05X}: {:04X} {:?}\\l", addr, oc.op_code, oc.oc_id));
+            }
+            let mut attrs = String::new();
+            if !self.reachable.contains(&block.start) {
+                attrs.push_str(", style=dashed, color=gray");
+            } else if self.subroutines.contains_key(&block.start) {
+                attrs.push_str(", style=bold");
+            }
+            if self.computed_jumps.contains(&(block.end - 1)) {
+                attrs.push_str(", color=red");
+            }
+            out.push_str(&format!("    b{:03X} [label=\"{}\"{}];\n", block.start, label.replace('"', "\\\""), attrs));
+        }
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [color=blue]",
+                    EdgeKind::Call => " [style=dashed, label=call]",
+                    EdgeKind::SkipTaken => " [color=darkgreen, label=skip]",
+                    EdgeKind::SkipNotTaken => " [color=orange]",
+                };
+                out.push_str(&format!("    b{:03X} -> b{:03X}{};\n", block.start, edge.target, style));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
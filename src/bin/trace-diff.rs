@@ -0,0 +1,24 @@
+//! Finds the first line where two execution traces written with `--trace` disagree
+
+use dexterws_skye_emulator::trace::first_divergence;
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    let [left, right] = paths.as_slice() else {
+        eprintln!("Usage: trace-diff <left.trace> <right.trace>");
+        std::process::exit(2);
+    };
+    let read = |path: &str| {
+        std::fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Error reading {}: {}", path, err);
+            std::process::exit(2);
+        })
+    };
+    match first_divergence(&read(left), &read(right)) {
+        Some(divergence) => {
+            println!("{}", divergence);
+            std::process::exit(1);
+        }
+        None => println!("traces are identical"),
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::{
+    cpu::Chip8,
+    expr::Expr,
+    parser::{OpCode, OpCodeIdentity, OpCodeType, ENCODINGS},
+    session::parse_address,
+    symbols::SymbolTable,
+};
+
+///What a breakpoint triggers on, checked before the instruction executes
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BreakKind {
+    Address(u16),
+    Identity(Vec<OpCodeIdentity>),  //Any of these instructions
+    Type(OpCodeType),               //Any instruction of this type, the payload is ignored
+    Always,                         //Every instruction, used with a condition
+}
+
+impl BreakKind {
+    ///Parses `<addr|label>`, `op <identity|mnemonic>`, `type <OpCodeType>` or nothing
+    pub fn parse(text: &str, symbols: Option<&SymbolTable>) -> Result<BreakKind, String> {
+        let mut tokens = text.split_whitespace();
+        match (tokens.next(), tokens.next()) {
+            (None, _) => Ok(BreakKind::Always),
+            (Some("op"), Some(name)) => {
+                let ids: Vec<OpCodeIdentity> = ENCODINGS
+                    .iter()
+                    .filter(|e| {
+                        format!("{:?}", e.oc_id).eq_ignore_ascii_case(name)
+                            || e.syntax.split_whitespace().next().is_some_and(|m| m.eq_ignore_ascii_case(name))
+                    })
+                    .map(|e| e.oc_id)
+                    .collect();
+                if ids.is_empty() {
+                    return Err(format!("unknown instruction `{}`", name));
+                }
+                Ok(BreakKind::Identity(ids))
+            }
+            (Some("type"), Some(name)) => OpCodeType::from_name(name)
+                .map(BreakKind::Type)
+                .ok_or_else(|| format!("unknown op code type `{}`", name)),
+            (Some(addr), None) => Ok(BreakKind::Address(parse_address(addr, symbols)?)),
+            _ => Err(format!("bad breakpoint `{}`", text)),
+        }
+    }
+
+    pub fn matches(&self, pc: u16, oc: Option<&OpCode>) -> bool {
+        match self {
+            BreakKind::Address(addr) => *addr == pc,
+            BreakKind::Identity(ids) => oc.is_some_and(|oc| ids.contains(&oc.oc_id)),
+            BreakKind::Type(oc_type) => oc.is_some_and(|oc| oc.oc_type.same_kind(oc_type)),
+            BreakKind::Always => true,
+        }
+    }
+}
+
+impl fmt::Display for BreakKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakKind::Address(addr) => write!(f, "{:#05X}", addr),
+            BreakKind::Identity(ids) if ids.len() == 1 => write!(f, "op {:?}", ids[0]),
+            BreakKind::Identity(ids) => write!(f, "op {:?}+{}", ids[0], ids.len() - 1),
+            BreakKind::Type(oc_type) => write!(f, "type {}", oc_type.name()),
+            BreakKind::Always => write!(f, "any"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub kind: BreakKind,
+    pub condition: Option<Expr>,
+    pub hits: u32,
+    pub temporary: bool,    //Deleted after the first hit
+    pub enabled: bool,
+}
+
+impl Breakpoint {
+    ///Parses `<kind> [if <condition>]`
+    pub fn parse(text: &str, symbols: Option<&SymbolTable>) -> Result<(BreakKind, Option<Expr>), String> {
+        let (kind, condition) = match text.find(" if ").map(|i| (&text[..i], &text[i + 4..])) {
+            Some((kind, condition)) => (kind, Some(condition)),
+            None => match text.trim().strip_prefix("if ") {
+                Some(condition) => ("", Some(condition)),
+                None => (text, None),
+            },
+        };
+        let condition = condition.map(Expr::parse).transpose()?;
+        Ok((BreakKind::parse(kind, symbols)?, condition))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {}", self.id, self.kind)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        write!(f, " hits={}", self.hits)?;
+        if self.temporary {
+            write!(f, " temp")?;
+        }
+        if !self.enabled {
+            write!(f, " off")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BreakpointSet {
+    list: Vec<Breakpoint>,
+    next_id: u32,
+}
+
+impl BreakpointSet {
+    pub fn add(&mut self, kind: BreakKind, condition: Option<Expr>, temporary: bool) -> u32 {
+        self.next_id += 1;
+        self.list.push(Breakpoint { id: self.next_id, kind, condition, hits: 0, temporary, enabled: true });
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.list.len() != len
+    }
+
+    ///Removes every breakpoint on an address
+    pub fn remove_address(&mut self, addr: u16) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.kind != BreakKind::Address(addr));
+        self.list.len() != len
+    }
+
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        self.list.iter_mut().find(|b| b.id == id).map(|b| b.enabled = enabled).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    ///Address breakpoints, used to mark the code panel
+    pub fn has_address(&self, addr: u16) -> bool {
+        self.list.iter().any(|b| b.enabled && b.kind == BreakKind::Address(addr))
+    }
+
+    ///First enabled breakpoint that would stop before the instruction about to run, without counting a hit
+    pub fn peek(&self, cpu: &Chip8) -> Option<u32> {
+        let pc = cpu.dump_pc();
+        let oc = cpu.dump_program().get(pc as usize);
+        self.list
+            .iter()
+            .find(|b| b.enabled && b.kind.matches(pc, oc) && b.condition.as_ref().is_none_or(|c| c.is_true(cpu)))
+            .map(|b| b.id)
+    }
+
+    ///Checks the instruction about to run, counting hits and dropping spent temporary breakpoints
+    pub fn check(&mut self, cpu: &Chip8) -> Option<u32> {
+        let pc = cpu.dump_pc();
+        let oc = cpu.dump_program().get(pc as usize);
+        let mut hit = None;
+        for bp in self.list.iter_mut().filter(|b| b.enabled) {
+            if !bp.kind.matches(pc, oc) || !bp.condition.as_ref().is_none_or(|c| c.is_true(cpu)) {
+                continue;
+            }
+            bp.hits += 1;
+            hit.get_or_insert(bp.id);
+        }
+        let hit = hit?;
+        self.list.retain(|b| !(b.temporary && b.hits > 0));
+        Some(hit)
+    }
+}
@@ -0,0 +1,242 @@
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+use crate::{
+    analysis::is_skip,
+    cpu::Chip8,
+    parser::OpCode,
+    symbols::SymbolTable,
+    writer::listing_line,
+};
+
+///Marker for an instruction that never ran, as gcov writes it
+const NEVER: &str = "#####";
+
+///How often a skip instruction went each way
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct SkipCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl SkipCount {
+    ///Directions seen so far, out of two
+    pub fn directions(&self) -> usize {
+        (self.taken > 0) as usize + (self.not_taken > 0) as usize
+    }
+}
+
+///Totals over the code of a program, data regions excluded
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct CoverageSummary {
+    pub instructions: usize,
+    pub executed: usize,
+    pub branches: usize,    //Two per skip instruction
+    pub branches_hit: usize,
+}
+
+impl fmt::Display for CoverageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |hit: usize, all: usize| hit as f64 * 100.0 / all.max(1) as f64;
+        write!(
+            f,
+            "{}/{} instructions ({:.1}%), {}/{} skip branches ({:.1}%)",
+            self.executed,
+            self.instructions,
+            percent(self.executed, self.instructions),
+            self.branches_hit,
+            self.branches,
+            percent(self.branches_hit, self.branches)
+        )
+    }
+}
+
+///Coverage of one source line, over every instruction assembled from it
+#[derive(Debug, Default, Clone)]
+struct LineCoverage {
+    count: u64,
+    skips: Vec<(u16, SkipCount)>,
+}
+
+///Records which instructions ran and which way every skip went
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    counts: Vec<u64>,   //Indexed by address
+    skips: BTreeMap<u16, SkipCount>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    pub fn skip(&self, addr: u16) -> SkipCount {
+        self.skips.get(&addr).copied().unwrap_or_default()
+    }
+
+    ///Records the instruction that just ran at `pc`
+    pub fn record(&mut self, pc: u16, oc: &OpCode, cpu: &Chip8) {
+        let index = pc as usize;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        if is_skip(oc.oc_id) {
+            let skip = self.skips.entry(pc).or_default();
+            if cpu.dump_pc() == pc.wrapping_add(2) {
+                skip.taken += 1;
+            } else {
+                skip.not_taken += 1;
+            }
+        }
+    }
+
+    ///Addresses of `program` holding code rather than data
+    fn code<'a>(&self, program: &'a [OpCode], symbols: Option<&'a SymbolTable>) -> impl Iterator<Item = (u16, &'a OpCode)> {
+        let is_data = move |addr: u16| symbols.is_some_and(|s| s.data_region(addr).is_some());
+        program.iter().enumerate().map(|(addr, oc)| (addr as u16, oc)).filter(move |(addr, _)| !is_data(*addr))
+    }
+
+    pub fn summary(&self, program: &[OpCode], symbols: Option<&SymbolTable>) -> CoverageSummary {
+        let mut summary = CoverageSummary::default();
+        for (addr, oc) in self.code(program, symbols) {
+            summary.instructions += 1;
+            summary.executed += (self.count(addr) > 0) as usize;
+            if is_skip(oc.oc_id) {
+                summary.branches += 2;
+                summary.branches_hit += self.skip(addr).directions();
+            }
+        }
+        summary
+    }
+
+    ///Execution count in front of a line, `#####` when it never ran
+    fn marker(count: u64) -> String {
+        if count == 0 {
+            NEVER.to_owned()
+        } else {
+            count.to_string()
+        }
+    }
+
+    fn describe_skip(skip: SkipCount) -> String {
+        format!("skip taken {}, not taken {}", skip.taken, skip.not_taken)
+    }
+
+    ///Disassembly of `program` with the execution count of every instruction, data shown as `-`
+    pub fn annotate_listing(&self, program: &[OpCode], symbols: Option<&SymbolTable>) -> String {
+        let mut out = format!("Coverage: {}\n", self.summary(program, symbols));
+        for (addr, oc) in program.iter().enumerate() {
+            let addr = addr as u16;
+            if let Some((label, 0)) = symbols.and_then(|s| s.label(addr)) {
+                out.push_str(&format!("{:>9}: {}:\n", "", label));
+            }
+            let data = symbols.is_some_and(|s| s.data_region(addr).is_some());
+            let marker = if data { "-".to_owned() } else { Self::marker(self.count(addr)) };
+            let mut line = format!("{:>9}: {}", marker, listing_line(addr, oc, symbols));
+            if is_skip(oc.oc_id) && !data {
+                line.push_str(&format!("  [{}]", Self::describe_skip(self.skip(addr))));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+
+    ///Code lines of every source file, from the debug symbols
+    fn source_lines(&self, program: &[OpCode], symbols: &SymbolTable) -> BTreeMap<String, BTreeMap<u32, LineCoverage>> {
+        let mut files: BTreeMap<String, BTreeMap<u32, LineCoverage>> = BTreeMap::new();
+        for (addr, oc) in self.code(program, Some(symbols)) {
+            let Some(loc) = symbols.source(addr) else { continue };
+            let line = files.entry(loc.file.clone()).or_default().entry(loc.line).or_default();
+            line.count = line.count.max(self.count(addr));
+            if is_skip(oc.oc_id) {
+                line.skips.push((addr, self.skip(addr)));
+            }
+        }
+        files
+    }
+
+    ///Source files the debug symbols point into
+    pub fn source_files(&self, program: &[OpCode], symbols: &SymbolTable) -> Vec<String> {
+        self.source_lines(program, symbols).into_keys().collect()
+    }
+
+    ///`text` of source file `file` with execution counts in front of its code lines, `-` elsewhere
+    pub fn annotate_source(&self, file: &str, text: &str, program: &[OpCode], symbols: &SymbolTable) -> String {
+        let files = self.source_lines(program, symbols);
+        let lines = files.get(file);
+        let mut out = String::new();
+        for (number, source) in text.lines().enumerate() {
+            let coverage = lines.and_then(|l| l.get(&(number as u32 + 1)));
+            let marker = coverage.map_or("-".to_owned(), |c| Self::marker(c.count));
+            out.push_str(&format!("{:>9}:{:>5}: {}", marker, number + 1, source));
+            for (_, skip) in coverage.map_or(&[][..], |c| &c.skips) {
+                out.push_str(&format!("  [{}]", Self::describe_skip(*skip)));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    ///Coverage in the lcov tracefile format, `None` without source lines to map it onto
+    pub fn to_lcov(&self, program: &[OpCode], symbols: &SymbolTable) -> Option<String> {
+        let files = self.source_lines(program, symbols);
+        if files.is_empty() {
+            return None;
+        }
+        let mut out = String::from("TN:\n");
+        for (file, lines) in files {
+            out.push_str(&format!("SF:{}\n", file));
+            let (mut branches, mut branches_hit) = (0, 0);
+            for (number, line) in &lines {
+                for (addr, skip) in &line.skips {
+                    // Branch 0 falls through, branch 1 skips. Lines that never ran have no counts
+                    for (branch, count) in [skip.not_taken, skip.taken].into_iter().enumerate() {
+                        let taken = if line.count == 0 { "-".to_owned() } else { count.to_string() };
+                        out.push_str(&format!("BRDA:{},{},{},{}\n", number, addr, branch, taken));
+                    }
+                    branches += 2;
+                    branches_hit += skip.directions();
+                }
+            }
+            if branches > 0 {
+                out.push_str(&format!("BRF:{}\nBRH:{}\n", branches, branches_hit));
+            }
+            for (number, line) in &lines {
+                out.push_str(&format!("DA:{},{}\n", number, line.count));
+            }
+            let hit = lines.values().filter(|l| l.count > 0).count();
+            out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        }
+        Some(out)
+    }
+
+    ///Writes a report in the format implied by the extension: `.info` lcov, `.lst` the annotated
+    ///disassembly, otherwise every source file annotated, read from `source_dir`. Without debug
+    ///source lines the last two fall back to the disassembly
+    pub fn save(&self, path: impl AsRef<Path>, program: &[OpCode], symbols: Option<&SymbolTable>, source_dir: &Path) -> io::Result<()> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str());
+        let files = symbols.map(|s| self.source_files(program, s)).unwrap_or_default();
+        let report = match (extension, symbols) {
+            (Some("info"), _) => symbols.and_then(|s| self.to_lcov(program, s)),
+            (Some("lst"), _) => Some(self.annotate_listing(program, symbols)),
+            (_, Some(symbols)) if !files.is_empty() => {
+                let mut out = format!("Coverage: {}\n", self.summary(program, Some(symbols)));
+                for file in files {
+                    let text = fs::read_to_string(source_dir.join(&file))?;
+                    out.push_str(&format!("\n--- {}\n", file));
+                    out.push_str(&self.annotate_source(&file, &text, program, symbols));
+                }
+                Some(out)
+            }
+            _ => Some(self.annotate_listing(program, symbols)),
+        };
+        let report = report.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "lcov output needs debug symbols with source lines"))?;
+        fs::write(path, report)
+    }
+}
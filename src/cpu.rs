@@ -1,4 +1,5 @@
-use crate::{display::{HEIGHT, WIDTH}, fastrand::Rand, parser::{DataType, OpCode, OpCodeIdentity, OpCodeType}};
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::unnecessary_cast, clippy::map_clone)]
+use crate::{display::{HEIGHT, WIDTH}, fastrand::Rand, parser::{DataType, OpCode, OpCodeIdentity}};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Stack {
@@ -35,6 +36,7 @@ impl Stack {
     }
 }
 
+#[derive(Clone)]
 struct VRAM {
     data: [u8; 64 * 32],
 }
@@ -62,6 +64,24 @@ impl VRAM {
 }
 
 
+///Direction of a memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+///Memory access made by the last instruction, `old` and `new` are equal for reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub kind: AccessKind,
+    pub old: u8,
+    pub new: u8,
+}
+
+///Complete machine state, cloning it makes a checkpoint
+#[derive(Clone)]
 pub struct Chip8 {
     pc: u16,
     registers_8bit: [u8; 16],
@@ -74,6 +94,8 @@ pub struct Chip8 {
     program: Vec<OpCode>,
     timer: u8,
     sound_timer: u8,
+    keys: u16,      //Pressed keys, bit N is key N
+    accesses: Vec<MemAccess>,
 }
 
 impl Default for Chip8 {
@@ -90,14 +112,38 @@ impl Default for Chip8 {
             vram_changed: false,
             timer: 0,
             sound_timer: 0,
+            keys: 0,
+            accesses: Vec::new(),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CPUError {
     StackOverflow,
+    StackUnderflow,
+    DataExecuted,
 }
 
+impl std::fmt::Display for CPUError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CPUError::StackOverflow => write!(f, "stack overflow"),
+            CPUError::StackUnderflow => write!(f, "return with empty stack"),
+            CPUError::DataExecuted => write!(f, "data word executed"),
+        }
+    }
+}
+
+///Changed VRAM (if any) and whether the sound timer is active, `None` once the program ends
+pub type CycleResult<'a> = Option<(Option<&'a [u8]>, bool)>;
+
+///A CPU error together with the address of the instruction that raised it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub pc: u16,
+    pub error: CPUError,
+}
 
 
 impl Chip8 {
@@ -108,10 +154,35 @@ impl Chip8 {
         }
     }
 
+    ///Like `new` but with a fixed random seed, so runs can be repeated exactly
+    pub fn with_seed(program: Vec<OpCode>, seed: u64) -> Self {
+        Self {
+            program,
+            rand_engine: Rand::new(seed),
+            ..Default::default()
+        }
+    }
+
     fn inc_pc(&mut self) {
         self.pc += 1;
     }
 
+    fn read_mem(&mut self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        self.accesses.push(MemAccess { addr, kind: AccessKind::Read, old: value, new: value });
+        value
+    }
+
+    fn write_mem(&mut self, addr: u16, value: u8) {
+        let old = self.memory[addr as usize];
+        self.memory[addr as usize] = value;
+        self.accesses.push(MemAccess { addr, kind: AccessKind::Write, old, new: value });
+    }
+
+    fn key_down(&self, key: u8) -> bool {
+        key < 16 && self.keys & (1 << key) != 0
+    }
+
     fn get_opcode(&mut self) -> Option<OpCode> {
         self.program.get(self.pc as usize).map(|x| *x)
     }
@@ -126,7 +197,7 @@ impl Chip8 {
                 self.vram_changed = true;
             }
             OpCodeIdentity::RetSub => {
-                self.pc = self.stack.pop().unwrap();
+                self.pc = self.stack.pop().ok_or(CPUError::StackUnderflow)?;
             }
             OpCodeIdentity::JumpAddr => {
                 if let DataType::NNN { address } = data {
@@ -170,7 +241,7 @@ impl Chip8 {
             }
             OpCodeIdentity::AddNcRC => {
                 if let DataType::XNN { x, constant } = data {
-                    self.registers_8bit[x as usize] += constant;
+                    self.registers_8bit[x as usize] = self.registers_8bit[x as usize].wrapping_add(constant);
                 }
             }
             OpCodeIdentity::SetRR => {
@@ -255,7 +326,7 @@ impl Chip8 {
                     let height = height as usize;
                     let mut collision = false;
                     for yline in 0..height {
-                        let pixel = self.memory[(self.register_12bit + yline as u16) as usize];
+                        let pixel = self.read_mem(self.register_12bit + yline as u16);
                         for xline in 0..8 {
                             if (pixel & (0x80 >> xline)) != 0 {
                                 collision = self.vram.flip(x + xline, y + yline);
@@ -266,14 +337,34 @@ impl Chip8 {
                 }
                 self.vram_changed = true;
             }
-            OpCodeIdentity::SkipKeyPressedR => unimplemented!(),
-            OpCodeIdentity::SkipNKeyPressedR => unimplemented!(),
+            OpCodeIdentity::SkipKeyPressedR => {
+                if let DataType::X { x } = data {
+                    if self.key_down(self.registers_8bit[x as usize]) {
+                        self.inc_pc();
+                    }
+                }
+            }
+            OpCodeIdentity::SkipNKeyPressedR => {
+                if let DataType::X { x } = data {
+                    if !self.key_down(self.registers_8bit[x as usize]) {
+                        self.inc_pc();
+                    }
+                }
+            }
             OpCodeIdentity::GetDelayR => {
                 if let DataType::X { x } = data {
                     self.registers_8bit[x as usize] = self.timer;
                 }
             }
-            OpCodeIdentity::AwaitGetKeyDownR => unimplemented!(),
+            OpCodeIdentity::AwaitGetKeyDownR => {
+                if let DataType::X { x } = data {
+                    match (0..16).find(|key| self.key_down(*key)) {
+                        Some(key) => self.registers_8bit[x as usize] = key,
+                        // Run the same instruction again until a key is down
+                        None => self.pc -= 1,
+                    }
+                }
+            }
             OpCodeIdentity::SetDelayR => {
                 if let DataType::X { x } = data {
                     self.timer = self.registers_8bit[x as usize];
@@ -293,29 +384,61 @@ impl Chip8 {
             OpCodeIdentity::SetBcdR => {
                 if let DataType::X { x } = data {
                     let x = self.registers_8bit[x as usize];
-                    self.memory[self.register_12bit as usize] = x / 100;
-                    self.memory[(self.register_12bit + 1) as usize] = (x / 10) % 10;
-                    self.memory[(self.register_12bit + 2) as usize] = x % 10;
+                    self.write_mem(self.register_12bit, x / 100);
+                    self.write_mem(self.register_12bit + 1, (x / 10) % 10);
+                    self.write_mem(self.register_12bit + 2, x % 10);
                 }
             }
             OpCodeIdentity::DumpRegsToMemR => {
                 if let DataType::X { x } = data {
                     for i in 0..=x {
-                        self.memory[(self.register_12bit + i as u16) as usize] = self.registers_8bit[i as usize];
+                        self.write_mem(self.register_12bit + i as u16, self.registers_8bit[i as usize]);
                     }
                 }
             }
             OpCodeIdentity::LoadRegsFromMemR => {
                 if let DataType::X { x } = data {
                     for i in 0..=x {
-                        self.registers_8bit[i as usize] = self.memory[(self.register_12bit + i as u16) as usize];
+                        self.registers_8bit[i as usize] = self.read_mem(self.register_12bit + i as u16);
                     }
                 }
             }
+            OpCodeIdentity::Data => return Err(CPUError::DataExecuted),
         }
         Ok(())
     }
 
+    pub fn set_register(&mut self, reg: usize, value: u8) {
+        self.registers_8bit[reg] = value;
+    }
+
+    pub fn set_large_register(&mut self, value: u16) {
+        self.register_12bit = value;
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_clock(&mut self, timer: u8, sound_timer: u8) {
+        self.timer = timer;
+        self.sound_timer = sound_timer;
+    }
+
+    ///Sets the pressed keys, bit N is key N
+    pub fn set_keys(&mut self, keys: u16) {
+        self.keys = keys;
+    }
+
+    pub fn dump_keys(&self) -> u16 {
+        self.keys
+    }
+
+    ///Writes memory from outside the program, not recorded as an access
+    pub fn poke_memory(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+    }
+
     pub fn dump_registers(&self) -> [u8; 16] {
         self.registers_8bit
     }
@@ -331,6 +454,19 @@ impl Chip8 {
     pub fn dump_clock(&self) -> (u8, u8) {
         (self.timer, self.sound_timer)
     }
+    pub fn dump_vram(&self) -> &[u8] {
+        &self.vram.data
+    }
+
+    pub fn dump_memory(&self) -> &[u8; 4096] {
+        &self.memory
+    }
+
+    ///Memory reads and writes made by the most recent cycle, in order
+    pub fn last_accesses(&self) -> &[MemAccess] {
+        &self.accesses
+    }
+
     pub fn dump_pc(&self) -> u16 {
         self.pc
     }
@@ -339,20 +475,28 @@ impl Chip8 {
         &self.program
     }
 
-    pub fn cycle(&mut self) -> Option<(Option<&[u8]>, bool)> {
-        self.vram_changed = false;
-        let oc = self.get_opcode()?;
-        let res = self.execute_op(oc);
-        match res {
-            Ok(_) => (),
-            Err(_) => panic!("CPU ERROR"),
+    pub fn cycle(&mut self) -> CycleResult<'_> {
+        match self.try_cycle() {
+            Ok(res) => res,
+            Err(fault) => panic!("CPU ERROR: {} at {:#05X}", fault.error, fault.pc),
         }
+    }
+
+    ///Like `cycle` but hands CPU errors back to the caller instead of panicking
+    pub fn try_cycle(&mut self) -> Result<CycleResult<'_>, Fault> {
+        self.vram_changed = false;
+        self.accesses.clear();
+        let pc = self.pc;
+        let Some(oc) = self.get_opcode() else {
+            return Ok(None);
+        };
+        self.execute_op(oc).map_err(|error| Fault { pc, error })?;
         self.timer = self.timer.saturating_sub(1);
         self.sound_timer = self.sound_timer.saturating_sub(1);
         if self.vram_changed {
-            return Some((Some(&self.vram.data), self.sound_timer > 0));
+            return Ok(Some((Some(&self.vram.data), self.sound_timer > 0)));
         }
-        Some((None, self.sound_timer > 0))
+        Ok(Some((None, self.sound_timer > 0)))
     }
 }
 
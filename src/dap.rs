@@ -0,0 +1,465 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, TryRecvError},
+};
+
+use crate::{
+    breakpoints::BreakKind,
+    display::{HEIGHT, WIDTH},
+    expr::Expr,
+    json::Json,
+    parser::parse_file,
+    session::{Command, Session, StopReason},
+    symbols::SymbolTable,
+};
+
+///Variable references of the scopes, every frame shows the same machine state
+const SCOPE_REGISTERS: u64 = 1;
+const SCOPE_TIMERS: u64 = 2;
+const SCOPE_STACK: u64 = 3;
+const SCOPE_MEMORY: u64 = 4;
+///The machine is presented to the client as a single thread
+const THREAD_ID: u64 = 1;
+///Instructions run between checks for a request from the client
+const POLL_INTERVAL: u32 = 1024;
+
+///Reads one `Content-Length` framed message. `None` when the client closed the stream
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "message without Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let text = String::from_utf8(body).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Json::parse(&text).map(Some).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(out: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+///Debug Adapter Protocol server, launching a program into a `Session` on request
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: u64,
+    session: Option<Session>,
+    symbols: SymbolTable,
+    source_dir: PathBuf,    //Directory of the program, source paths are relative to it
+    stop_on_entry: bool,
+    source_breakpoints: BTreeMap<String, Vec<u32>>, //Breakpoint ids set for each source path
+    events: Vec<Json>,      //Sent after the response to the current request
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 0,
+            session: None,
+            symbols: SymbolTable::new(),
+            source_dir: PathBuf::new(),
+            stop_on_entry: false,
+            source_breakpoints: BTreeMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub fn into_session(self) -> Option<Session> {
+        self.session
+    }
+
+    ///Serves requests read from `input` until the client disconnects
+    pub fn serve(&mut self, input: impl Read + Send + 'static) -> io::Result<()> {
+        // Requests are read on a separate thread so a running program can be paused
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(input);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let running = self.session.as_ref().is_some_and(|s| s.is_running());
+            let request = if running {
+                self.run(POLL_INTERVAL)?;
+                match rx.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match rx.recv() {
+                    Ok(request) => request,
+                    Err(_) => return Ok(()),
+                }
+            };
+            if !self.handle(&request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send(&mut self, kind: &str, mut fields: Vec<(String, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq".to_owned(), self.seq.into()));
+        fields.insert(1, ("type".to_owned(), kind.into()));
+        write_message(&mut self.out, &Json::Object(fields))
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.events.push(Json::object([("event", event.into()), ("body", body)]));
+    }
+
+    fn flush_events(&mut self) -> io::Result<()> {
+        for event in std::mem::take(&mut self.events) {
+            let Json::Object(fields) = event else { continue };
+            self.send("event", fields)?;
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, description: String) {
+        let body = Json::object([
+            ("reason", reason.into()),
+            ("description", description.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]);
+        self.event("stopped", body);
+    }
+
+    ///Answers one request, returning false once the client disconnected
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").as_str().unwrap_or_default().to_owned();
+        let result = self.dispatch(&command, request.get("arguments"));
+        let mut fields = vec![
+            ("request_seq".to_owned(), request.get("seq").clone()),
+            ("success".to_owned(), result.is_ok().into()),
+            ("command".to_owned(), command.as_str().into()),
+        ];
+        match result {
+            Ok(body) => fields.push(("body".to_owned(), body)),
+            Err(message) => fields.push(("message".to_owned(), message.into())),
+        }
+        self.send("response", fields)?;
+        self.flush_events()?;
+        Ok(!matches!(command.as_str(), "disconnect" | "terminate"))
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Json) -> Result<Json, String> {
+        match command {
+            "initialize" => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsConditionalBreakpoints", true.into()),
+                ("supportsEvaluateForHovers", true.into()),
+                ("supportsTerminateRequest", true.into()),
+            ])),
+            "launch" => self.launch(args),
+            "disconnect" => Ok(Json::Null),
+            "terminate" => {
+                self.event("terminated", Json::object([]));
+                Ok(Json::Null)
+            }
+            "threads" => {
+                let thread = Json::object([("id", THREAD_ID.into()), ("name", "CHIP-8".into())]);
+                Ok(Json::object([("threads", vec![thread].into())]))
+            }
+            "setExceptionBreakpoints" => Ok(Json::object([("breakpoints", Json::Array(Vec::new()))])),
+            _ => {
+                if self.session.is_none() {
+                    return Err(format!("`{}` needs a launched program", command));
+                }
+                self.dispatch_launched(command, args)
+            }
+        }
+    }
+
+    fn dispatch_launched(&mut self, command: &str, args: &Json) -> Result<Json, String> {
+        match command {
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", "stopped on entry".to_owned());
+                } else {
+                    self.resume(&Command::Continue);
+                }
+                Ok(Json::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "continue" => {
+                self.resume(&Command::Continue);
+                Ok(Json::object([("allThreadsContinued", true.into())]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let step = match command {
+                    "next" => Command::StepOver,
+                    "stepIn" => Command::Step(1),
+                    _ => Command::StepOut,
+                };
+                self.resume(&step);
+                Ok(Json::Null)
+            }
+            "pause" => {
+                if let Some(stop) = self.session_mut().execute(&Command::Pause) {
+                    self.report_stop(stop);
+                }
+                Ok(Json::Null)
+            }
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => {
+                let scope = |name: &str, reference: u64, expensive: bool| {
+                    Json::object([
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", expensive.into()),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Registers", SCOPE_REGISTERS, false),
+                    scope("Timers", SCOPE_TIMERS, false),
+                    scope("Stack", SCOPE_STACK, false),
+                    scope("Memory", SCOPE_MEMORY, true),
+                ];
+                Ok(Json::object([("scopes", scopes.into())]))
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").as_u64().ok_or("missing variablesReference")?;
+                let variables: Vec<Json> = self
+                    .variables(reference)
+                    .into_iter()
+                    .map(|(name, value)| {
+                        Json::object([("name", name.into()), ("value", value.into()), ("variablesReference", 0u64.into())])
+                    })
+                    .collect();
+                Ok(Json::object([("variables", variables.into())]))
+            }
+            "evaluate" => {
+                let expr = Expr::parse(args.get("expression").as_str().unwrap_or_default())?;
+                let value = expr.eval(self.session().expect("launched").cpu());
+                Ok(Json::object([("result", format!("{:#X} ({})", value, value).into()), ("variablesReference", 0u64.into())]))
+            }
+            // Custom request for the display contents, one string of `#` and `.` per row
+            "framebuffer" => {
+                let vram = self.session().expect("launched").cpu().dump_vram();
+                let rows: Vec<Json> = (0..HEIGHT)
+                    .map(|y| (0..WIDTH).map(|x| if vram[x + y * WIDTH] == 1 { '#' } else { '.' }).collect::<String>().into())
+                    .collect();
+                Ok(Json::object([("width", (WIDTH as u64).into()), ("height", (HEIGHT as u64).into()), ("rows", rows.into())]))
+            }
+            _ => Err(format!("unsupported request `{}`", command)),
+        }
+    }
+
+    fn session_mut(&mut self) -> &mut Session {
+        self.session.as_mut().expect("launched")
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let program = args.get("program").as_str().ok_or("launch needs a `program`")?;
+        if !Path::new(program).is_file() {
+            return Err(format!("cannot open `{}`", program));
+        }
+        let parsed = parse_file(program).map_err(|e| format!("cannot load `{}`: {}", program, e))?;
+        self.symbols = SymbolTable::for_program(program).map_err(|e| format!("cannot load symbols for `{}`: {}", program, e))?;
+        self.session = Some(match args.get("seed").as_u64() {
+            Some(seed) => Session::with_seed(parsed, seed),
+            None => Session::new(parsed),
+        });
+        self.source_dir = Path::new(program).parent().unwrap_or(Path::new("")).to_path_buf();
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        // Ready for the breakpoints now that there are symbols to place them with
+        self.event("initialized", Json::object([]));
+        Ok(Json::Null)
+    }
+
+    ///Starts running with `command`, a step that can't start stops straight away
+    fn resume(&mut self, command: &Command) {
+        let session = self.session_mut();
+        let stop = session.execute(command);
+        let running = session.is_running();
+        match stop {
+            Some(stop) => self.report_stop(stop),
+            None if !running => self.stopped("step", "nothing to step out of".to_owned()),
+            None => (),
+        }
+    }
+
+    ///Runs up to `cycles` instructions, sending the stop event if the program stops
+    fn run(&mut self, cycles: u32) -> io::Result<()> {
+        for _ in 0..cycles {
+            if let Some(stop) = self.session_mut().tick().stop {
+                self.report_stop(stop);
+                break;
+            }
+        }
+        self.flush_events()
+    }
+
+    fn report_stop(&mut self, stop: StopReason) {
+        let description = stop.describe(Some(&self.symbols));
+        match stop {
+            StopReason::Halted => {
+                self.event("exited", Json::object([("exitCode", 0u64.into())]));
+                self.event("terminated", Json::object([]));
+            }
+            StopReason::Breakpoint(id) => {
+                let body = Json::object([
+                    ("reason", "breakpoint".into()),
+                    ("description", description.into()),
+                    ("threadId", THREAD_ID.into()),
+                    ("allThreadsStopped", true.into()),
+                    ("hitBreakpointIds", vec![Json::from(id as u64)].into()),
+                ]);
+                self.event("stopped", body);
+            }
+            StopReason::Watch(_) => self.stopped("data breakpoint", description),
+            StopReason::Fault(_) => self.stopped("exception", description),
+            StopReason::Paused => self.stopped("pause", description),
+            _ => self.stopped("step", description),
+        }
+    }
+
+    ///Source file of `file` as named in the debug symbols
+    fn source(&self, file: &str) -> Json {
+        let path = self.source_dir.join(file);
+        Json::object([("name", file.into()), ("path", path.to_string_lossy().into_owned().into())])
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("source").get("path").as_str().ok_or("setBreakpoints needs a source path")?.to_owned();
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            self.session_mut().breakpoints_mut().remove(id);
+        }
+        // Code lines of this source and their first address
+        let mut lines: BTreeMap<u32, u16> = BTreeMap::new();
+        for (addr, loc) in self.symbols.sources() {
+            if Path::new(&path).ends_with(&loc.file) {
+                let first = lines.entry(loc.line).or_insert(addr);
+                *first = addr.min(*first);
+            }
+        }
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let line = breakpoint.get("line").as_u64().unwrap_or(0) as u32;
+            let condition = match breakpoint.get("condition").as_str().map(Expr::parse) {
+                Some(Err(err)) => {
+                    results.push(Json::object([("verified", false.into()), ("line", (line as u64).into()), ("message", err.into())]));
+                    continue;
+                }
+                Some(Ok(expr)) => Some(expr),
+                None => None,
+            };
+            // A line without code moves to the next one that has some
+            let Some((&line, &addr)) = lines.range(line..).next() else {
+                let message = "no code at or after this line";
+                results.push(Json::object([("verified", false.into()), ("line", (line as u64).into()), ("message", message.into())]));
+                continue;
+            };
+            let id = self.session_mut().breakpoints_mut().add(BreakKind::Address(addr), condition, false);
+            ids.push(id);
+            results.push(Json::object([
+                ("id", (id as u64).into()),
+                ("verified", true.into()),
+                ("line", (line as u64).into()),
+                ("instructionReference", format!("{:#05X}", addr).into()),
+            ]));
+        }
+        self.source_breakpoints.insert(path, ids);
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn stack_trace(&self) -> Json {
+        let session = self.session().expect("launched");
+        let mut frames = vec![session.cpu().dump_pc()];
+        frames.extend(session.backtrace().iter().map(|frame| frame.call_site));
+        let frames: Vec<Json> = frames
+            .into_iter()
+            .enumerate()
+            .map(|(id, addr)| {
+                let name = match self.symbols.label(addr) {
+                    Some((label, _)) => label.to_owned(),
+                    None => format!("{:#05X}", addr),
+                };
+                let loc = self.symbols.source(addr);
+                let mut frame = Json::object([
+                    ("id", (id as u64).into()),
+                    ("name", name.into()),
+                    ("line", loc.map_or(0, |loc| loc.line as u64).into()),
+                    ("column", 1u64.into()),
+                    ("instructionPointerReference", format!("{:#05X}", addr).into()),
+                ]);
+                // Frames without debug info only have an address
+                if let (Some(loc), Json::Object(fields)) = (loc, &mut frame) {
+                    fields.push(("source".to_owned(), self.source(&loc.file)));
+                }
+                frame
+            })
+            .collect();
+        let total = frames.len() as u64;
+        Json::object([("stackFrames", frames.into()), ("totalFrames", total.into())])
+    }
+
+    fn variables(&self, reference: u64) -> Vec<(String, String)> {
+        let session = self.session().expect("launched");
+        let cpu = session.cpu();
+        let describe = |addr: u16| self.symbols.describe(addr);
+        match reference {
+            SCOPE_REGISTERS => {
+                let mut variables: Vec<(String, String)> =
+                    cpu.dump_registers().iter().enumerate().map(|(i, v)| (format!("V{:X}", i), format!("{:#04X}", v))).collect();
+                variables.push(("I".to_owned(), format!("{:#05X}", cpu.dump_large_register())));
+                variables.push(("PC".to_owned(), describe(cpu.dump_pc())));
+                variables
+            }
+            SCOPE_TIMERS => {
+                let (timer, sound_timer) = cpu.dump_clock();
+                vec![
+                    ("DT".to_owned(), format!("{:#04X}", timer)),
+                    ("ST".to_owned(), format!("{:#04X}", sound_timer)),
+                    ("cycles".to_owned(), session.cycles().to_string()),
+                ]
+            }
+            SCOPE_STACK => {
+                let mut variables = vec![("SP".to_owned(), cpu.dump_stack().head.to_string())];
+                for (depth, frame) in session.backtrace().iter().enumerate() {
+                    variables.push((format!("#{}", depth + 1), format!("{} calls {}", describe(frame.call_site), describe(frame.target))));
+                }
+                variables
+            }
+            SCOPE_MEMORY => cpu
+                .dump_memory()
+                .chunks(16)
+                .enumerate()
+                .map(|(row, bytes)| {
+                    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    (format!("{:#05X}", row * 16), bytes.join(" "))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
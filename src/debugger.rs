@@ -1,81 +1,556 @@
-use crate::{cpu::Stack, parser::OpCode};
+use crate::{analysis::{successors, EdgeKind}, breakpoints::BreakpointSet, cpu::Fault, layout::{Layout, Panel}, parser::OpCode, profile::Profiler, screen::Screen, session::{Draw, Frame, RunState, Session, StopReason}, symbols::SymbolTable, watchpoints::WatchpointSet};
 
 
-pub struct Debugger {
-    locations: DebugLocations,
+///Width of the stack panel before the clock panel starts
+const STACK_WIDTH: usize = 19;
+///Deepest call stack the CPU allows
+const STACK_DEPTH: usize = 48;
+
+///Most rows of disassembly in the code panel
+pub const CODE_ROWS: usize = 30;
+
+///First program row shown by the code panel, keeping the PC centered until either end is reached
+pub fn code_window_start(pc: u16, len: usize, rows: usize) -> usize {
+    (pc as usize).saturating_sub(rows / 2).min(len.saturating_sub(rows))
+}
+
+///Width of the history scrubber bar
+const HISTORY_BAR: usize = 32;
+///Timeline rows below the scrubber bar
+const HISTORY_ROWS: usize = 14;
+
+///Rows shown by the memory panel
+pub const MEMORY_ROWS: usize = 16;
+const MEMORY_SIZE: usize = 4096;
+
+///Where the memory panel looks
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MemoryAnchor {
+    FollowI,
+    Pinned(u16),
 }
 
-pub struct DebugLocations {
-    pub reg_locations: (usize, usize),
-    pub code_locations: (usize, usize),
-    pub large_reg_location: (usize, usize),
-    pub stack_location: (usize, usize),
-    pub clock_location: (usize, usize),
+///View commands for the memory panel
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MemoryCommand {
+    Follow,
+    Pin(u16),
+    Scroll(i32),    //Rows, negative scrolls up
+    Sprite(bool),   //Show bytes as 8 pixel wide sprite rows
+}
+
+pub struct MemoryView {
+    pub anchor: MemoryAnchor,
+    pub sprite: bool,
+    previous: Box<[u8; MEMORY_SIZE]>,  //Memory as of the last step, to highlight changes
+}
+
+impl Default for MemoryView {
+    fn default() -> Self {
+        Self {
+            anchor: MemoryAnchor::FollowI,
+            sprite: false,
+            previous: Box::new([0; MEMORY_SIZE]),
+        }
+    }
+}
+
+impl MemoryView {
+    ///First address shown, keeping `I` a few rows from the top when following it
+    pub fn start(&self, addr_reg: u16) -> u16 {
+        let bytes_per_row = if self.sprite { 1 } else { 16 };
+        let rows = MEMORY_ROWS as u16 * bytes_per_row;
+        let start = match self.anchor {
+            MemoryAnchor::FollowI => (addr_reg & !(bytes_per_row - 1)).saturating_sub(bytes_per_row * 2),
+            MemoryAnchor::Pinned(addr) => addr & !(bytes_per_row - 1),
+        };
+        start.min(MEMORY_SIZE as u16 - rows)
+    }
+}
+
+///View commands for the sprite panel
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SpriteCommand {
+    Follow,         //Start at the sprite drawn last
+    Pin(u16),
+    Scroll(i32),    //Sprites, negative scrolls up
+    Height(u8),     //Rows of the 8 pixel wide sprites
+    Wide(bool),     //SCHIP 16x16 sprites
+    Hide,
+}
+
+///Where the sprite panel looks and how it cuts memory into sprites
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct SpriteView {
+    pub anchor: Option<u16>,    //`None` follows the last draw
+    pub height: u8,
+    pub wide: bool,
+}
+
+impl Default for SpriteView {
+    fn default() -> Self {
+        Self { anchor: None, height: 5, wide: false }
+    }
+}
+
+impl SpriteView {
+    ///Width and height of one sprite. Following a draw uses its height, where 0 is SCHIP's 16x16
+    pub fn size(&self, last_draw: Option<Draw>) -> (usize, usize) {
+        match (self.anchor, last_draw) {
+            (None, Some(draw)) if draw.height == 0 => (16, 16),
+            (None, Some(draw)) => (8, draw.height as usize),
+            _ if self.wide => (16, 16),
+            _ => (8, self.height as usize),
+        }
+    }
+
+    ///Address of the first sprite shown
+    pub fn start(&self, last_draw: Option<Draw>) -> u16 {
+        self.anchor.or(last_draw.map(|draw| draw.addr)).unwrap_or(0)
+    }
+}
+
+///View commands for the panel layout
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LayoutCommand {
+    Compact(bool),  //Show only the game screen
+    NextTab,        //Cycle the panels that had to share space
+}
+
+pub struct Debugger {
+    layout: Layout,
+    panels: Vec<Panel>, //Panels to lay out when not compact
+    compact: bool,
+    tab: usize,
+    symbols: Option<SymbolTable>,
+    memory_view: MemoryView,
+    sprite_view: SpriteView,
 }
 
 impl Debugger {
-    pub fn new(locations: DebugLocations) -> Self {
+    ///Debugger laid out for a terminal of `size` columns and rows
+    pub fn new(size: (usize, usize)) -> Self {
         Self {
-            locations
+            layout: Layout::compute(size.0, size.1, &Panel::DEFAULT, 0),
+            panels: Panel::DEFAULT.to_vec(),
+            compact: false,
+            tab: 0,
+            symbols: None,
+            memory_view: Default::default(),
+            sprite_view: Default::default(),
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    ///Lays the panels out again for a new terminal size, returns whether anything moved
+    pub fn resize(&mut self, size: (usize, usize)) -> bool {
+        let shown = if self.compact { &[][..] } else { &self.panels };
+        let layout = Layout::compute(size.0, size.1, shown, self.tab);
+        let changed = layout != self.layout;
+        self.layout = layout;
+        changed
+    }
+
+    ///Adds a panel that isn't shown by default, like the profile
+    pub fn show_panel(&mut self, panel: Panel) {
+        if !self.panels.contains(&panel) {
+            self.panels.push(panel);
+            self.resize(self.layout.size);
+        }
+    }
+
+    pub fn hide_panel(&mut self, panel: Panel) {
+        self.panels.retain(|p| *p != panel);
+        self.resize(self.layout.size);
+    }
+
+    pub fn apply_layout_command(&mut self, command: LayoutCommand) {
+        match command {
+            LayoutCommand::Compact(compact) => self.compact = compact,
+            LayoutCommand::NextTab => self.tab = (self.tab + 1) % self.layout.tabs.len().max(1),
+        }
+        self.resize(self.layout.size);
+    }
+
+    pub fn memory_view(&self) -> &MemoryView {
+        &self.memory_view
+    }
+
+    pub fn apply_memory_command(&mut self, command: MemoryCommand, addr_reg: u16) {
+        let view = &mut self.memory_view;
+        match command {
+            MemoryCommand::Follow => view.anchor = MemoryAnchor::FollowI,
+            MemoryCommand::Pin(addr) => view.anchor = MemoryAnchor::Pinned(addr),
+            MemoryCommand::Scroll(rows) => {
+                let step = if view.sprite { 1 } else { 16 };
+                let start = view.start(addr_reg) as i32 + rows * step;
+                view.anchor = MemoryAnchor::Pinned(start.clamp(0, MEMORY_SIZE as i32 - 1) as u16);
+            }
+            MemoryCommand::Sprite(sprite) => view.sprite = sprite,
+        }
+    }
+
+    pub fn sprite_view(&self) -> &SpriteView {
+        &self.sprite_view
+    }
+
+    ///Changes the sprite panel, showing it unless the command hides it
+    pub fn apply_sprite_command(&mut self, command: SpriteCommand, last_draw: Option<Draw>) {
+        let view = &mut self.sprite_view;
+        match command {
+            SpriteCommand::Follow => view.anchor = None,
+            SpriteCommand::Pin(addr) => view.anchor = Some(addr),
+            SpriteCommand::Scroll(sprites) => {
+                let (width, height) = view.size(last_draw);
+                let start = view.start(last_draw) as i32 + sprites * (width / 8 * height) as i32;
+                view.anchor = Some(start.clamp(0, MEMORY_SIZE as i32 - 1) as u16);
+            }
+            SpriteCommand::Height(height) => {
+                view.height = height;
+                view.wide = false;
+            }
+            SpriteCommand::Wide(wide) => view.wide = wide,
+            SpriteCommand::Hide => return self.hide_panel(Panel::Sprites),
+        }
+        // Changing how memory is cut up stops following the draw, whose shape would win
+        if matches!(command, SpriteCommand::Height(_) | SpriteCommand::Wide(_)) && view.anchor.is_none() {
+            view.anchor = Some(view.start(last_draw));
         }
+        self.show_panel(Panel::Sprites);
     }
 
-    pub fn print_registers(&self, registers: &[u8; 16], large_reg: u16) {
-        let reg_loc = self.locations.reg_locations;
-        let large_reg_loc = self.locations.large_reg_location;
+    ///Remembers the current memory so the next redraw highlights what a step changed
+    pub fn mark_memory(&mut self, memory: &[u8; MEMORY_SIZE]) {
+        self.memory_view.previous.copy_from_slice(memory);
+    }
+
+    pub fn print_memory(&self, out: &mut Screen, memory: &[u8; MEMORY_SIZE], addr_reg: u16) {
+        let Some(rect) = self.layout.rect(Panel::Memory) else { return };
+        let view = &self.memory_view;
+        let start = view.start(addr_reg) as usize;
+        let anchor = match view.anchor {
+            MemoryAnchor::FollowI => "following I".to_owned(),
+            MemoryAnchor::Pinned(addr) => format!("at {:#05X}", addr),
+        };
+        out.print(format_args!("\x1B[{};{}HMemory ({}):\x1B[K", rect.y, rect.x, anchor));
+        // Changed bytes are shown inverted and the byte at I in bold
+        let style = |addr: usize| match (memory[addr] != view.previous[addr], addr == addr_reg as usize) {
+            (true, _) => "\x1B[7m",
+            (false, true) => "\x1B[1m",
+            _ => "",
+        };
+        for row in 0..MEMORY_ROWS.min(rect.height - 1) {
+            out.print(format_args!("\x1B[{};{}H", rect.y + row + 1, rect.x));
+            if view.sprite {
+                let addr = start + row;
+                let pixels: String = (0..8).map(|bit| if memory[addr] & (0x80 >> bit) != 0 { '█' } else { '·' }).collect();
+                out.print(format_args!("{:#05X}: {}{:02X}\x1B[0m {}\x1B[K", addr, style(addr), memory[addr], pixels));
+                continue;
+            }
+            let addr = start + row * 16;
+            out.print(format_args!("{:#05X}:", addr));
+            for (offset, byte) in memory[addr..addr + 16].iter().enumerate() {
+                out.print(format_args!(" {}{:02X}\x1B[0m", style(addr + offset), byte));
+            }
+            let ascii: String = memory[addr..addr + 16]
+                .iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect();
+            out.print(format_args!("  {}\x1B[K", ascii));
+        }
+    }
+
+    pub fn load_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_ref()
+    }
+
+    ///Symbolized form of `addr` if debug symbols are loaded, bare hex otherwise
+    pub fn describe_address(&self, addr: u16) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{:#05X}", addr),
+        }
+    }
+
+    ///Short form of an address: `label+offset` when symbols are loaded, else hex
+    pub fn label_address(&self, addr: u16) -> String {
+        match self.symbols.as_ref().and_then(|s| s.label(addr)) {
+            Some((name, 0)) => name.to_owned(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:#05X}", addr),
+        }
+    }
+
+    pub fn print_registers(&self, out: &mut Screen, registers: &[u8; 16], large_reg: u16) {
+        let Some(rect) = self.layout.rect(Panel::Registers) else { return };
         // Goto
-        print!("\x1B[{};{}H", reg_loc.1, reg_loc.0);
-        print!("8bit registers:");
-        for i in 0..16 {
-            print!("\x1B[{};{}H", reg_loc.1 + i + 1, reg_loc.0);
-            print!("V{:X} = {:#04X} ", i, registers[i as usize]);
-        }
-        print!("\x1B[{};{}H", large_reg_loc.1, large_reg_loc.0);
-        print!("12bit register:");
-        print!("\x1B[{};{}H", large_reg_loc.1 + 1, large_reg_loc.0);
-        println!("I = {:#05X}", large_reg);
-    }
-
-    pub fn print_stack(&self, stack: &Stack) {
-        let stack_loc = self.locations.stack_location;
-        print!("\x1B[{};{}H", stack_loc.1, stack_loc.0);
-        print!("Stack:");
-        for i in 0..stack.data.len() {
-            print!("\x1B[{};{}H", stack_loc.1 + i + 1, stack_loc.0);
-            if i >= stack.head as usize {
-                print!("        ");
+        out.print(format_args!("\x1B[{};{}H", rect.y, rect.x));
+        out.print(format_args!("8bit registers:"));
+        for (i, reg) in registers.iter().enumerate() {
+            out.print(format_args!("\x1B[{};{}H", rect.y + i + 1, rect.x));
+            out.print(format_args!("V{:X} = {:#04X} ", i, reg));
+        }
+        out.print(format_args!("\x1B[{};{}H", rect.y + 17, rect.x));
+        out.print(format_args!("12bit register:"));
+        out.print(format_args!("\x1B[{};{}H", rect.y + 18, rect.x));
+        out.print(format_args!("I = {:#05X}", large_reg));
+    }
+
+    ///Backtrace with the current location on top, then each call site and its target
+    pub fn print_stack(&self, out: &mut Screen, frames: &[Frame], pc: u16) {
+        let Some(rect) = self.layout.rect(Panel::Stack) else { return };
+        out.print(format_args!("\x1B[{};{}H", rect.y, rect.x));
+        out.print(format_args!("Stack:"));
+        let mut rows = vec![format!("#0 {}", self.label_address(pc))];
+        for (depth, frame) in frames.iter().enumerate() {
+            rows.push(format!("#{} {}>{}", depth + 1, self.label_address(frame.call_site), self.label_address(frame.target)));
+        }
+        for i in 0..=STACK_DEPTH.min(rect.height - 2) {
+            out.print(format_args!("\x1B[{};{}H", rect.y + i + 1, rect.x));
+            let row = rows.get(i).map_or("", |r| r.get(..STACK_WIDTH).unwrap_or(r));
+            out.print(format_args!("{:<width$}", row, width = STACK_WIDTH));
+        }
+    }
+
+    ///Disassembly around the PC. Rows show the address, breakpoint and PC markers, the op code,
+    ///its mnemonic and an arrow towards the target of any jump, call or skip
+    pub fn print_codes(&self, out: &mut Screen, program: &[OpCode], pc: u16, breakpoints: &BreakpointSet) {
+        let Some(rect) = self.layout.rect(Panel::Code) else { return };
+        out.print(format_args!("\x1B[{};{}H Program: {}\x1B[K", rect.y, rect.x, self.describe_address(pc)));
+        // Where the instruction at the PC may go next, marked in the gutter
+        let pc_targets: Vec<u16> = program
+            .get(pc as usize)
+            .map(|oc| successors(oc, pc).iter().filter(|e| e.kind != EdgeKind::Fallthrough).map(|e| e.target).collect())
+            .unwrap_or_default();
+        let rows = CODE_ROWS.min(rect.height - 1);
+        let start = code_window_start(pc, program.len(), rows);
+        for row in 0..rows {
+            out.print(format_args!("\x1B[{};{}H", rect.y + row + 1, rect.x));
+            let addr = start + row;
+            let Some(oc) = program.get(addr) else {
+                out.print(format_args!("\x1B[K"));
                 continue;
+            };
+            let addr = addr as u16;
+            let bp = if breakpoints.has_address(addr) { '●' } else { ' ' };
+            let point = match (addr == pc, pc_targets.contains(&addr)) {
+                (true, _) => '>',
+                (false, true) => '→',
+                _ => ' ',
+            };
+            let arrow = successors(oc, addr)
+                .iter()
+                .find(|e| matches!(e.kind, EdgeKind::Jump | EdgeKind::Call | EdgeKind::SkipTaken))
+                .map(|e| format!("{} {}", if e.target <= addr { '↑' } else { '↓' }, self.label_address(e.target)))
+                .unwrap_or_default();
+            let style = if addr == pc { "\x1B[7m" } else { "" };
+            out.print(format_args!("{}{}{}{:03X}  {:04X}  {:<16}\x1B[0m {}\x1B[K", bp, point, style, addr, oc.op_code, oc.mnemonic(), arrow));
+        }
+    }
+
+    pub fn print_fault(&self, out: &mut Screen, fault: &Fault) {
+        let status_loc = self.layout.status;
+        out.print(format_args!("\x1B[{};{}H", status_loc.1, status_loc.0));
+        out.print(format_args!("CPU fault: {} at {}\x1B[K\n", fault.error, self.describe_address(fault.pc)));
+    }
+
+    ///Draws every visible panel. Left panels go first since their rows clear to the line end
+    pub fn print_panels(&self, out: &mut Screen, session: &Session) {
+        let cpu = session.cpu();
+        let mut panels = self.layout.panels().to_vec();
+        panels.sort_by_key(|(_, rect)| rect.x);
+        self.print_tab_bar(out);
+        for (panel, _) in panels {
+            match panel {
+                Panel::Registers => self.print_registers(out, &cpu.dump_registers(), cpu.dump_large_register()),
+                Panel::Stack => self.print_stack(out, &session.backtrace(), cpu.dump_pc()),
+                Panel::Clock => self.print_clock(out, cpu.dump_clock()),
+                Panel::Code => self.print_codes(out, cpu.dump_program(), cpu.dump_pc(), session.breakpoints()),
+                Panel::Memory => self.print_memory(out, cpu.dump_memory(), cpu.dump_large_register()),
+                Panel::History => self.print_history(out, session),
+                Panel::Profile => self.print_profile(out, session.profiler()),
+                Panel::Sprites => self.print_sprites(out, cpu.dump_memory(), session.last_draw()),
+                Panel::Breakpoints => self.print_breakpoints(out, session.breakpoints(), session.watchpoints()),
             }
-            print!("{:#05X} ", stack.data[i as usize]);
-        }
-    }
-    
-    pub fn print_codes(&self, program:&[OpCode], pc:u16){
-        let prog_loc = self.locations.code_locations;
-        let list_len=if 30>program.len()as u16 {program.len() as u16} else {30};
-        let disp_start = (pc/list_len)*list_len;
-        print!("\x1b[{};{}H Program:", prog_loc.1, prog_loc.0);
-        for n in 0..list_len{
-            print!("\x1b[{};{}H {:#02X}:                  ",prog_loc.1+1+n as usize,prog_loc.0,pc);
-            if disp_start+n<program.len() as u16{
-                let mut point=" ";
-                if disp_start+n==pc{
-                    point=">";
-                }
-                print!("\x1b[{};{}H{}{:#02X}:{:#04X}",prog_loc.1+1+n as usize,prog_loc.0,point,pc,program[(disp_start+n) as usize].op_code);
+        }
+    }
+
+    ///Names of the panels sharing space, the shown one bracketed, plus any collapsed panels
+    pub fn print_tab_bar(&self, out: &mut Screen) {
+        let Some(bar) = self.layout.tab_bar else { return };
+        let active = self.layout.active_tab();
+        let tabs: Vec<String> = self
+            .layout
+            .tabs
+            .iter()
+            .map(|tab| if Some(*tab) == active { format!("[{}]", tab.name()) } else { tab.name().to_owned() })
+            .collect();
+        let mut text = format!("{} (tab)", tabs.join(" "));
+        if !self.layout.collapsed.is_empty() {
+            let hidden: Vec<&str> = self.layout.collapsed.iter().map(|p| p.name()).collect();
+            text = format!("{}  hidden: {}", text, hidden.join(" "));
+        }
+        let text: String = text.chars().take(bar.width).collect();
+        out.print(format_args!("\x1B[{};{}H{:<width$}", bar.y, bar.x, text, width = bar.width));
+    }
+
+    ///Memory cut into sprites, with the last draw and where it went on top
+    pub fn print_sprites(&self, out: &mut Screen, memory: &[u8; MEMORY_SIZE], last_draw: Option<Draw>) {
+        let Some(rect) = self.layout.rect(Panel::Sprites) else { return };
+        let view = &self.sprite_view;
+        let (width, height) = view.size(last_draw);
+        let start = view.start(last_draw) as usize;
+        let bytes = width / 8 * height;
+        let anchor = if view.anchor.is_some() { "pinned" } else { "following last draw" };
+        let mut lines = vec![format!("Sprites {}x{} at {:#05X} ({}):", width, height, start, anchor)];
+        lines.push(match last_draw {
+            Some(draw) => format!(
+                "Last draw {}x{} from {:#05X} at ({}, {}) by {}",
+                if draw.height == 0 { 16 } else { 8 },
+                if draw.height == 0 { 16 } else { draw.height },
+                draw.addr,
+                draw.x,
+                draw.y,
+                self.label_address(draw.pc)
+            ),
+            None => "No sprite drawn yet".to_owned(),
+        });
+        // Sprites side by side, each under its address
+        let across = ((rect.width + 1) / (width + 1)).max(1);
+        let down = rect.height.saturating_sub(2) / (height + 1);
+        for block in 0..down {
+            let first = start + block * across * bytes;
+            let addresses: Vec<String> = (0..across).map(|i| format!("{:<width$}", format!("{:03X}", first + i * bytes))).collect();
+            lines.push(addresses.join(" "));
+            for row in 0..height {
+                let sprites: Vec<String> = (0..across)
+                    .map(|i| {
+                        let addr = first + i * bytes + row * width / 8;
+                        let bits = match width {
+                            16 => u16::from_be_bytes([*memory.get(addr).unwrap_or(&0), *memory.get(addr + 1).unwrap_or(&0)]),
+                            _ => (*memory.get(addr).unwrap_or(&0) as u16) << 8,
+                        };
+                        (0..width).map(|bit| if bits & (0x8000 >> bit) != 0 { '█' } else { '·' }).collect()
+                    })
+                    .collect();
+                lines.push(sprites.join(" "));
             }
+        }
+        lines.resize(rect.height, String::new());
+        for (row, line) in lines.iter().enumerate() {
+            let line: String = line.chars().take(rect.width).collect();
+            out.print(format_args!("\x1B[{};{}H{}\x1B[K", rect.y + row, rect.x, line));
+        }
+    }
 
+    ///Most executed addresses on top, the costliest subroutines below them
+    pub fn print_profile(&self, out: &mut Screen, profiler: Option<&Profiler>) {
+        let (Some(rect), Some(profiler)) = (self.layout.rect(Panel::Profile), profiler) else { return };
+        let width = rect.width;
+        let share = |n: u64| n as f64 * 100.0 / profiler.executed().max(1) as f64;
+        let mut lines = vec![format!("Profile ({} instructions):", profiler.executed())];
+        let spots = (rect.height - 1) / 2;
+        lines.extend(profiler.hot_spots().into_iter().take(spots.saturating_sub(1)).map(|(addr, count)| {
+            format!("{:>9} {:>5.1}% {}", count, share(count), self.describe_address(addr))
+        }));
+        lines.resize(spots + 1, String::new());
+        lines.push(format!("{:>9} {:>9}  Subroutine", "Incl", "Excl"));
+        lines.extend(profiler.subroutines().into_iter().map(|(addr, cost)| {
+            format!("{:>9} {:>9}  {}", cost.inclusive, cost.exclusive, self.describe_address(addr))
+        }));
+        lines.resize(rect.height, String::new());
+        for (row, line) in lines.iter().enumerate() {
+            out.print(format_args!("\x1B[{};{}H{:<width$.width$}", rect.y + row, rect.x, line, width = width));
         }
-        
     }
 
-    pub fn print_clock(&self, clocks: (u8, u8)) {
-        let clock_loc = self.locations.clock_location;
-        print!("\x1B[{};{}H", clock_loc.1, clock_loc.0);
-        print!("Clocks:");
-        print!("\x1B[{};{}H", clock_loc.1 + 1, clock_loc.0);
-        println!("Delay: {:#04X}", clocks.0);
-        print!("\x1B[{};{}H", clock_loc.1 + 2, clock_loc.0);
-        println!("Sound: {:#04X}", clocks.1);
+    ///Timeline of executed instructions with a scrubber bar showing where the current cycle
+    ///sits in the recorded history
+    pub fn print_history(&self, out: &mut Screen, session: &Session) {
+        let Some(rect) = self.layout.rect(Panel::History) else { return };
+        let history_loc = (rect.x, rect.y);
+        let rows = HISTORY_ROWS.min(rect.height - 2);
+        let history = session.history();
+        let (earliest, latest, now) = (history.earliest(), history.latest().max(session.cycles()), session.cycles());
+        out.print(format_args!("\x1B[{};{}HHistory: cycle {} of {}..{}\x1B[K", history_loc.1, history_loc.0, now, earliest, latest));
+        let span = (latest - earliest).max(1);
+        let marker = ((now - earliest) * (HISTORY_BAR as u64 - 1) / span) as usize;
+        let bar: String = (0..HISTORY_BAR).map(|i| if i == marker { '|' } else if i < marker { '=' } else { '-' }).collect();
+        out.print(format_args!("\x1B[{};{}H[{}]\x1B[K", history_loc.1 + 1, history_loc.0, bar));
+        // Executed instructions leading up to the current cycle and any recorded after it
+        let before = history.timeline().rev().filter(|e| e.cycle < now).take(rows.saturating_sub(2)).count();
+        let entries = history.timeline().filter(|e| e.cycle + before as u64 >= now).take(rows);
+        let mut row = history_loc.1 + 2;
+        for entry in entries {
+            let mnemonic = OpCode::decode(entry.op_code).map(|oc| oc.mnemonic()).unwrap_or_default();
+            let point = if entry.cycle == now { '>' } else { ' ' };
+            out.print(format_args!("\x1B[{};{}H{}{:>8} {:#05X} {:04X} {}\x1B[K", row, history_loc.0, point, entry.cycle, entry.pc, entry.op_code, mnemonic));
+            row += 1;
+        }
+        for row in row..history_loc.1 + 2 + rows {
+            out.print(format_args!("\x1B[{};{}H\x1B[K", row, history_loc.0));
+        }
+    }
+
+    pub fn print_breakpoints(&self, out: &mut Screen, breakpoints: &BreakpointSet, watchpoints: &WatchpointSet) {
+        let Some(rect) = self.layout.rect(Panel::Breakpoints) else { return };
+        out.print(format_args!("\x1B[{};{}H", rect.y, rect.x));
+        out.print(format_args!("Breakpoints:\x1B[K"));
+        let last = rect.y + rect.height.min(self.layout.size.1 - rect.y + 1);
+        let mut row = rect.y + 1;
+        let entries = breakpoints.iter().map(|bp| bp.to_string()).chain(watchpoints.iter().map(|wp| wp.to_string()));
+        for entry in entries.take(last - row) {
+            out.print(format_args!("\x1B[{};{}H{}\x1B[K", row, rect.x, entry));
+            row += 1;
+        }
+        // Clear what is left of a longer list
+        if row < last {
+            out.print(format_args!("\x1B[{};{}H\x1B[K", row, rect.x));
+        }
+    }
+
+    ///Status line for the interactive mode, `stop` is the reason of the last pause
+    pub fn print_status(&self, out: &mut Screen, state: RunState, stop: Option<&StopReason>, pc: u16) {
+        let status_loc = self.layout.status;
+        out.print(format_args!("\x1B[{};{}H", status_loc.1, status_loc.0));
+        let state = match state {
+            RunState::Paused => "Paused",
+            RunState::Running => "Running",
+            RunState::Stepping(_) => "Stepping",
+            RunState::RunningTo(_) => "Running to address",
+            RunState::Returning(_) => "Finishing subroutine",
+        };
+        let reason = stop.map_or(String::new(), |stop| stop.describe(self.symbols()));
+        out.print(format_args!("{} at {} {}\x1B[K", state, self.describe_address(pc), reason));
+    }
+
+    ///Outcome of the last command or hotkey, on the row below the status line
+    pub fn print_message(&self, out: &mut Screen, message: &str) {
+        let status_loc = self.layout.status;
+        out.print(format_args!("\x1B[{};{}H{}\x1B[K", status_loc.1 + 1, status_loc.0, message));
+    }
+
+    ///Moves to the prompt line below the status and shows `message` above the input
+    pub fn print_prompt(&self, out: &mut Screen, message: &str) {
+        let status_loc = self.layout.status;
+        self.print_message(out, message);
+        out.print(format_args!("\x1B[{};{}H> \x1B[K", status_loc.1 + 2, status_loc.0));
+    }
+
+    pub fn print_clock(&self, out: &mut Screen, clocks: (u8, u8)) {
+        let Some(rect) = self.layout.rect(Panel::Clock) else { return };
+        out.print(format_args!("\x1B[{};{}H", rect.y, rect.x));
+        out.print(format_args!("Clocks:"));
+        out.print(format_args!("\x1B[{};{}H", rect.y + 1, rect.x));
+        out.print(format_args!("Delay: {:#04X}", clocks.0));
+        out.print(format_args!("\x1B[{};{}H", rect.y + 2, rect.x));
+        out.print(format_args!("Sound: {:#04X}", clocks.1));
     }
 }
@@ -1,15 +1,442 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{png, screen::Screen, theme::Theme};
+
 pub const HEIGHT: usize = 32;
 pub const WIDTH: usize = 64;
-pub struct Display;
+
+///Resolution of the frames a backend is sent
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize,
+}
+
+///The standard 64x32 CHIP-8 screen
+pub const LORES: Mode = Mode { width: WIDTH, height: HEIGHT };
+
+impl Default for Mode {
+    fn default() -> Self {
+        LORES
+    }
+}
+
+///Receives what the emulated screen shows
+pub trait DisplayBackend {
+    ///Called before the first frame and whenever the resolution changes
+    fn set_mode(&mut self, mode: Mode);
+
+    ///Colors to show frames in, backends without one pick their own
+    fn set_theme(&mut self, _theme: &Theme) {}
+
+    ///Shows a frame, one byte per pixel row by row. Pixels are 0 when dark, otherwise the
+    ///bitplanes they are lit in, 1 being the only plane of plain CHIP-8
+    fn draw(&mut self, vram: &[u8]);
+
+    ///Shows the current frame again after something else drew over it, only terminals need to
+    fn redraw(&mut self, _vram: &[u8]) {}
+
+    ///Reports the first error hit while showing frames
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///Characters the terminal renderer draws pixels with
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum TextStyle {
+    #[default]
+    Block,      //One full block per pixel
+    HalfBlock,  //▀ and ▄, two pixels stacked in a cell
+    Braille,    //2x4 pixels per cell
+    Ascii,      //`#` per pixel, for terminals without Unicode
+}
+
+impl TextStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "block" => Some(TextStyle::Block),
+            "half" => Some(TextStyle::HalfBlock),
+            "braille" => Some(TextStyle::Braille),
+            "ascii" => Some(TextStyle::Ascii),
+            _ => None,
+        }
+    }
+
+    ///Pixels covered by one character, as (columns, rows)
+    pub fn cell(self) -> (usize, usize) {
+        match self {
+            TextStyle::Block | TextStyle::Ascii => (1, 1),
+            TextStyle::HalfBlock => (1, 2),
+            TextStyle::Braille => (2, 4),
+        }
+    }
+
+    ///Terminal columns and rows a frame of `mode` takes
+    pub fn footprint(self, mode: Mode) -> (usize, usize) {
+        let (w, h) = self.cell();
+        (mode.width.div_ceil(w), mode.height.div_ceil(h))
+    }
+
+    ///Character for the cell whose top left pixel is at `x`, `y`, with the pixel values giving
+    ///its foreground and background colors
+    fn glyph(self, mode: Mode, vram: &[u8], x: usize, y: usize) -> (char, u8, u8) {
+        let pixel = |dx: usize, dy: usize| {
+            if x + dx < mode.width && y + dy < mode.height { vram[x + dx + (y + dy) * mode.width] } else { 0 }
+        };
+        match self {
+            TextStyle::Block | TextStyle::Ascii => match pixel(0, 0) {
+                0 => (' ', 0, 0),
+                lit => (if self == TextStyle::Block { '█' } else { '#' }, lit, 0),
+            },
+            TextStyle::HalfBlock => match (pixel(0, 0), pixel(0, 1)) {
+                (0, 0) => (' ', 0, 0),
+                (top, 0) => ('▀', top, 0),
+                (0, bottom) => ('▄', bottom, 0),
+                (top, bottom) if top == bottom => ('█', top, 0),
+                // XO-CHIP planes can light both halves in different colors
+                (top, bottom) => ('▀', top, bottom),
+            },
+            TextStyle::Braille => {
+                // Dots 1-3 and 4-6 run down the columns, 7 and 8 are the bottom row
+                const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+                let (mut bits, mut color) = (0, 0);
+                for (dx, column) in DOTS.iter().enumerate() {
+                    for (dy, dot) in column.iter().enumerate() {
+                        if pixel(dx, dy) != 0 {
+                            bits |= dot;
+                            color = color.max(pixel(dx, dy));
+                        }
+                    }
+                }
+                // Blank cells stay spaces, some fonts draw the empty pattern as faint dots
+                if bits == 0 { (' ', 0, 0) } else { (char::from_u32(0x2800 + bits).unwrap_or(' '), color, 0) }
+            }
+        }
+    }
+
+    ///Lines of text showing a frame
+    pub fn render(self, mode: Mode, vram: &[u8]) -> Vec<String> {
+        let (w, h) = self.cell();
+        (0..mode.height)
+            .step_by(h)
+            .map(|y| (0..mode.width).step_by(w).map(|x| self.glyph(mode, vram, x, y).0).collect())
+            .collect()
+    }
+
+    ///Lines of text showing a frame in the colors of `theme`, each ending in the default style
+    pub fn render_colored(self, mode: Mode, vram: &[u8], theme: &Theme) -> Vec<String> {
+        let (w, h) = self.cell();
+        let mut lines = Vec::new();
+        for y in (0..mode.height).step_by(h) {
+            let mut line = String::new();
+            let mut colors = None;
+            for x in (0..mode.width).step_by(w) {
+                let (ch, fg, bg) = self.glyph(mode, vram, x, y);
+                // A space only shows its background
+                let fg = if ch == ' ' { colors.map_or(fg, |(fg, _)| fg) } else { fg };
+                if colors != Some((fg, bg)) {
+                    line.push_str("\x1B[0m");
+                    line.push_str(&theme.escape(theme.color(fg), false));
+                    line.push_str(&theme.escape(theme.color(bg), true));
+                    colors = Some((fg, bg));
+                }
+                line.push(ch);
+            }
+            line.push_str("\x1B[0m");
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+///Draws frames in the terminal with ANSI escapes, top left. Only the cells that changed since
+///the previous frame are written, leaving the cursor where it was
+pub struct Display {
+    mode: Mode,
+    style: TextStyle,
+    theme: Option<Theme>,   //Terminal colors without one
+    screen: Screen,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new(TextStyle::default())
+    }
+}
 
 impl Display {
-    pub fn draw(vram: &[u8]) {
-        print!("\x1B[1;1H");
-        for y in 0..32 {
-            for x in 0..64 {
-                print!("{}", if vram[x + y * 64] == 1 { "█" } else { " " });
+    pub fn new(style: TextStyle) -> Self {
+        Self { mode: LORES, style, theme: None, screen: Screen::new(style.footprint(LORES)) }
+    }
+
+    pub fn theme(&self) -> Option<&Theme> {
+        self.theme.as_ref()
+    }
+
+    ///Escapes bringing the terminal from the previous frame to this one, empty if they match
+    pub fn frame_diff(&mut self, vram: &[u8]) -> String {
+        let lines = match &self.theme {
+            Some(theme) => self.style.render_colored(self.mode, vram, theme),
+            None => self.style.render(self.mode, vram),
+        };
+        for (row, line) in lines.iter().enumerate() {
+            self.screen.print(format_args!("\x1B[{};1H{}", row + 1, line));
+        }
+        let diff = self.screen.diff();
+        // The cursor is saved and restored around the changes, so it is never where they left it
+        self.screen.forget_cursor();
+        if diff.is_empty() {
+            return diff;
+        }
+        format!("\x1B7{}\x1B8", diff)
+    }
+}
+
+impl DisplayBackend for Display {
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.screen.resize(self.style.footprint(mode));
+    }
+
+    fn draw(&mut self, vram: &[u8]) {
+        let diff = self.frame_diff(vram);
+        if !diff.is_empty() {
+            let mut stdout = io::stdout().lock();
+            // A closed terminal has nowhere to show the error either
+            let _ = stdout.write_all(diff.as_bytes()).and_then(|_| stdout.flush());
+        }
+    }
+
+    fn set_theme(&mut self, theme: &Theme) {
+        self.theme = Some(theme.clone());
+    }
+
+    fn redraw(&mut self, vram: &[u8]) {
+        self.screen.resize(self.screen.size());
+        self.draw(vram);
+    }
+}
+
+///Discards every frame, for running without a screen
+#[derive(Default)]
+pub struct NullDisplay;
+
+impl DisplayBackend for NullDisplay {
+    fn set_mode(&mut self, _mode: Mode) {}
+
+    fn draw(&mut self, _vram: &[u8]) {}
+}
+
+///What a recording backend was sent
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DisplayEvent {
+    Mode(Mode),
+    Theme(Theme),
+    Frame(Vec<u8>),
+}
+
+///Keeps every mode change and frame in memory
+#[derive(Debug, Default)]
+pub struct RecordingDisplay {
+    pub events: Vec<DisplayEvent>,
+}
+
+impl RecordingDisplay {
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> {
+        self.events.iter().filter_map(|e| match e {
+            DisplayEvent::Frame(vram) => Some(vram.as_slice()),
+            _ => None,
+        })
+    }
+}
+
+impl DisplayBackend for RecordingDisplay {
+    fn set_mode(&mut self, mode: Mode) {
+        self.events.push(DisplayEvent::Mode(mode));
+    }
+
+    fn set_theme(&mut self, theme: &Theme) {
+        self.events.push(DisplayEvent::Theme(theme.clone()));
+    }
+
+    fn draw(&mut self, vram: &[u8]) {
+        self.events.push(DisplayEvent::Frame(vram.to_vec()));
+    }
+}
+
+///Formats frames can be written in
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ImageFormat {
+    Pbm,    //Plain bitmap, 1 is a lit pixel
+    Ppm,    //Binary RGB in the colors of a theme
+    Png,    //Indexed color with the theme as palette
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    ///Format implied by the extension of `path`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "pbm" => Some(ImageFormat::Pbm),
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self, mode: Mode, vram: &[u8], theme: &Theme) -> Vec<u8> {
+        match self {
+            ImageFormat::Pbm => encode_pbm(mode, vram),
+            ImageFormat::Ppm => {
+                let mut out = format!("P6\n{} {}\n255\n", mode.width, mode.height).into_bytes();
+                for pixel in &vram[..mode.width * mode.height] {
+                    let color = theme.color(*pixel);
+                    out.extend([color.0, color.1, color.2]);
+                }
+                out
             }
-            println!();
+            ImageFormat::Png => {
+                let pixels: Vec<u8> = vram[..mode.width * mode.height].iter().map(|p| p & 3).collect();
+                png::encode_indexed(mode.width, mode.height, &theme.planes, &pixels)
+            }
+        }
+    }
+}
+
+///Frame blown up `scale` times in both directions, `None` when the size overflows
+pub fn scale_frame(mode: Mode, vram: &[u8], scale: usize) -> Option<(Mode, Vec<u8>)> {
+    let scaled = Mode { width: mode.width.checked_mul(scale)?, height: mode.height.checked_mul(scale)? };
+    let mut out = Vec::with_capacity(scaled.width.checked_mul(scaled.height)?);
+    for row in vram[..mode.width * mode.height].chunks(mode.width) {
+        let line: Vec<u8> = row.iter().flat_map(|p| std::iter::repeat_n(*p, scale)).collect();
+        for _ in 0..scale {
+            out.extend_from_slice(&line);
+        }
+    }
+    Some((scaled, out))
+}
+
+///Scale screenshots are saved at unless told otherwise
+pub const SCREENSHOT_SCALE: usize = 4;
+///Largest screenshot scale, a 64 x 32 frame at it is about 8 megapixels
+pub const MAX_SCREENSHOT_SCALE: usize = 64;
+
+///Where a screenshot taken at `cycle` goes when no path is given
+pub fn screenshot_path(cycle: u64) -> String {
+    format!("screenshot-{}.png", cycle)
+}
+
+///Saves a frame at `scale` in the format implied by the extension: `.png` in the colors of
+///`theme`, `.pbm` as a plain bitmap or `.ppm`
+pub fn save_screenshot(path: impl AsRef<Path>, mode: Mode, vram: &[u8], theme: &Theme, scale: usize) -> io::Result<()> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "screenshots are .png, .pbm or .ppm files"))?;
+    if !(1..=MAX_SCREENSHOT_SCALE).contains(&scale) {
+        let message = format!("screenshot scale must be 1 to {}", MAX_SCREENSHOT_SCALE);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let (mode, vram) = scale_frame(mode, vram, scale)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "screenshot too large"))?;
+    fs::write(path, format.encode(mode, &vram, theme))
+}
+
+///Longest line the PBM format allows
+const PBM_LINE: usize = 70;
+
+///Plain PBM text, every row of pixels starting a new line and wrapped to fit the line limit
+pub fn encode_pbm(mode: Mode, vram: &[u8]) -> Vec<u8> {
+    let mut out = format!("P1\n{} {}\n", mode.width, mode.height);
+    for row in vram[..mode.width * mode.height].chunks(mode.width) {
+        // A pixel and the space after it take two characters, the last one needs no space
+        for line in row.chunks(PBM_LINE.div_ceil(2)) {
+            let pixels: Vec<&str> = line.iter().map(|p| if *p != 0 { "1" } else { "0" }).collect();
+            out.push_str(&pixels.join(" "));
+            out.push('\n');
+        }
+    }
+    out.into_bytes()
+}
+
+///Writes every frame to a numbered image file in a directory
+pub struct ImageDisplay {
+    dir: PathBuf,
+    format: ImageFormat,
+    mode: Mode,
+    theme: Theme,
+    frames: u64,
+    error: Option<io::Error>,   //First write error, frames are dropped after it
+}
+
+impl ImageDisplay {
+    pub fn create(dir: impl AsRef<Path>, format: ImageFormat) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir: dir.as_ref().to_path_buf(), format, mode: LORES, theme: Theme::default(), frames: 0, error: None })
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl DisplayBackend for ImageDisplay {
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    fn set_theme(&mut self, theme: &Theme) {
+        self.theme = theme.clone();
+    }
+
+    fn draw(&mut self, vram: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let path = self.dir.join(format!("frame{:06}.{}", self.frames, self.format.extension()));
+        match fs::write(path, self.format.encode(self.mode, vram, &self.theme)) {
+            Ok(()) => self.frames += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+///Backend named on the command line: `ansi` with an optional `:block`, `:half`, `:braille` or
+///`:ascii` style, `null`, or `pbm:<dir>`, `ppm:<dir>` and `png:<dir>` for image files
+pub fn backend_from_name(name: &str) -> Result<Box<dyn DisplayBackend>, String> {
+    let (kind, arg) = name.split_once(':').unwrap_or((name, ""));
+    let image = |format: ImageFormat| -> Result<Box<dyn DisplayBackend>, String> {
+        if arg.is_empty() {
+            return Err(format!("`{}` needs a directory, e.g. `{}:frames`", kind, kind));
+        }
+        let display = ImageDisplay::create(arg, format).map_err(|e| format!("cannot create {}: {}", arg, e))?;
+        Ok(Box::new(display))
+    };
+    match kind {
+        "ansi" if arg.is_empty() => Ok(Box::new(Display::default())),
+        "ansi" => {
+            let style = TextStyle::from_name(arg).ok_or_else(|| format!("unknown style `{}`, use block, half, braille or ascii", arg))?;
+            Ok(Box::new(Display::new(style)))
         }
+        "null" => Ok(Box::new(NullDisplay)),
+        "pbm" => image(ImageFormat::Pbm),
+        "ppm" => image(ImageFormat::Ppm),
+        "png" => image(ImageFormat::Png),
+        _ => Err(format!("unknown display `{}`", name)),
     }
-}
\ No newline at end of file
+}
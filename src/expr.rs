@@ -0,0 +1,284 @@
+use std::fmt;
+
+use crate::cpu::Chip8;
+
+///Machine state an expression can name
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Operand {
+    Register(u8),
+    AddrReg,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BinOp {
+    Mul, Div, Rem,
+    Add, Sub,
+    Shl, Shr,
+    Lt, Le, Gt, Ge,
+    Eq, Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+impl BinOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 10,
+            BinOp::Add | BinOp::Sub => 9,
+            BinOp::Shl | BinOp::Shr => 8,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 7,
+            BinOp::Eq | BinOp::Ne => 6,
+            BinOp::BitAnd => 5,
+            BinOp::BitXor => 4,
+            BinOp::BitOr => 3,
+            BinOp::And => 2,
+            BinOp::Or => 1,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Mul => "*", BinOp::Div => "/", BinOp::Rem => "%",
+            BinOp::Add => "+", BinOp::Sub => "-",
+            BinOp::Shl => "<<", BinOp::Shr => ">>",
+            BinOp::Lt => "<", BinOp::Le => "<=", BinOp::Gt => ">", BinOp::Ge => ">=",
+            BinOp::Eq => "==", BinOp::Ne => "!=",
+            BinOp::BitAnd => "&", BinOp::BitXor => "^", BinOp::BitOr => "|",
+            BinOp::And => "&&", BinOp::Or => "||",
+        }
+    }
+}
+
+///Expression over registers, timers and memory, e.g. `V3 == 0x10 && [I+2] > 5`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Number(i64),
+    Operand(Operand),
+    Memory(Box<Expr>),      //Byte at the address
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const SYMBOLS: [&str; 23] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "*", "/", "%", "+", "-", "<", ">", "&", "^", "|", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap_or_default();
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                tokens.push(Token::Number(value.map_err(|_| format!("bad number `{}`", word))?));
+            } else {
+                tokens.push(Token::Ident(word.to_owned()));
+            }
+            rest = &rest[len..];
+        } else if let Some(op) = SYMBOLS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("unexpected `{}`", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn operand(name: &str) -> Option<Operand> {
+    let upper = name.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Some(Operand::AddrReg),
+        "PC" => Some(Operand::Pc),
+        "SP" => Some(Operand::Sp),
+        "DT" => Some(Operand::Delay),
+        "ST" => Some(Operand::Sound),
+        _ => {
+            let reg = upper.strip_prefix('V')?;
+            if reg.len() != 1 {
+                return None;
+            }
+            u8::from_str_radix(reg, 16).ok().map(Operand::Register)
+        }
+    }
+}
+
+fn bin_op(symbol: &str) -> Option<BinOp> {
+    let op = match symbol {
+        "*" => BinOp::Mul, "/" => BinOp::Div, "%" => BinOp::Rem,
+        "+" => BinOp::Add, "-" => BinOp::Sub,
+        "<<" => BinOp::Shl, ">>" => BinOp::Shr,
+        "<" => BinOp::Lt, "<=" => BinOp::Le, ">" => BinOp::Gt, ">=" => BinOp::Ge,
+        "==" => BinOp::Eq, "!=" => BinOp::Ne,
+        "&" => BinOp::BitAnd, "^" => BinOp::BitXor, "|" => BinOp::BitOr,
+        "&&" => BinOp::And, "||" => BinOp::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(found)) if found == op => Ok(()),
+            _ => Err(format!("expected `{}`", op)),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Ident(name)) => operand(&name)
+                .map(Expr::Operand)
+                .ok_or_else(|| format!("unknown name `{}`", name)),
+            Some(Token::Op("(")) => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(Token::Op("[")) => {
+                let inner = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(inner)))
+            }
+            Some(Token::Op("-")) => Ok(Expr::Neg(Box::new(self.primary()?))),
+            Some(Token::Op("!")) => Ok(Expr::Not(Box::new(self.primary()?))),
+            Some(Token::Op(op)) => Err(format!("unexpected `{}`", op)),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+
+    ///Precedence climbing over left-associative binary operators
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.primary()?;
+        while let Some(Token::Op(symbol)) = self.peek() {
+            let Some(op) = bin_op(symbol).filter(|op| op.precedence() > min_precedence) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.binary(op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let expr = parser.binary(0)?;
+        if parser.pos < parser.tokens.len() {
+            return Err("trailing input in expression".to_owned());
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, cpu: &Chip8) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Operand(operand) => match operand {
+                Operand::Register(reg) => cpu.dump_registers()[*reg as usize] as i64,
+                Operand::AddrReg => cpu.dump_large_register() as i64,
+                Operand::Pc => cpu.dump_pc() as i64,
+                Operand::Sp => cpu.dump_stack().head as i64,
+                Operand::Delay => cpu.dump_clock().0 as i64,
+                Operand::Sound => cpu.dump_clock().1 as i64,
+            },
+            Expr::Memory(addr) => {
+                let memory = cpu.dump_memory();
+                memory[addr.eval(cpu).rem_euclid(memory.len() as i64) as usize] as i64
+            }
+            Expr::Neg(inner) => inner.eval(cpu).wrapping_neg(),
+            Expr::Not(inner) => (inner.eval(cpu) == 0) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu);
+                // Short-circuit the logical operators
+                match op {
+                    BinOp::And if lhs == 0 => return 0,
+                    BinOp::Or if lhs != 0 => return 1,
+                    _ => (),
+                }
+                let rhs = rhs.eval(cpu);
+                match op {
+                    BinOp::Mul => lhs.wrapping_mul(rhs),
+                    BinOp::Div => lhs.checked_div(rhs).unwrap_or(0),
+                    BinOp::Rem => lhs.checked_rem(rhs).unwrap_or(0),
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::BitXor => lhs ^ rhs,
+                    BinOp::BitOr => lhs | rhs,
+                    BinOp::And | BinOp::Or => (rhs != 0) as i64,
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &Chip8) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{:#X}", value),
+            Expr::Operand(Operand::Register(reg)) => write!(f, "V{:X}", reg),
+            Expr::Operand(Operand::AddrReg) => write!(f, "I"),
+            Expr::Operand(Operand::Pc) => write!(f, "PC"),
+            Expr::Operand(Operand::Sp) => write!(f, "SP"),
+            Expr::Operand(Operand::Delay) => write!(f, "DT"),
+            Expr::Operand(Operand::Sound) => write!(f, "ST"),
+            Expr::Memory(addr) => write!(f, "[{}]", addr),
+            Expr::Neg(inner) => write!(f, "-{}", inner),
+            Expr::Not(inner) => write!(f, "!{}", inner),
+            Expr::Binary(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 
 
+#[derive(Clone)]
 pub struct Rand {
     seed: u64,
 }
@@ -23,7 +24,7 @@ impl Rand {
 
     pub fn rand(&mut self) -> u64 {
         let state = self.seed.wrapping_mul(747796405).wrapping_add(2891336453);
-        let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+        let word = (state.wrapping_shr(((state >> 28) + 4) as u32) ^ state).wrapping_mul(277803737);
         let result = (word >> 22) ^ word;
         self.seed = result;
         result
@@ -0,0 +1,363 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    breakpoints::BreakKind,
+    session::{Command, Session, StopReason},
+};
+
+///Register numbers as exposed to the debugger frontend
+pub const REG_I: usize = 16;
+pub const REG_PC: usize = 17;
+pub const REG_SP: usize = 18;
+pub const REG_DT: usize = 19;
+pub const REG_ST: usize = 20;
+pub const REG_COUNT: usize = 21;
+
+///Instructions run between polls for an interrupt from the client
+const POLL_INTERVAL: u32 = 1024;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.skye.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+fn reg_size(reg: usize) -> usize {
+    if reg == REG_I || reg == REG_PC { 2 } else { 1 }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+///Reads one register as little-endian bytes
+fn read_reg(session: &Session, reg: usize) -> Vec<u8> {
+    let cpu = session.cpu();
+    match reg {
+        0..=15 => vec![cpu.dump_registers()[reg]],
+        REG_I => cpu.dump_large_register().to_le_bytes().to_vec(),
+        REG_PC => cpu.dump_pc().to_le_bytes().to_vec(),
+        REG_SP => vec![cpu.dump_stack().head],
+        REG_DT => vec![cpu.dump_clock().0],
+        _ => vec![cpu.dump_clock().1],
+    }
+}
+
+fn write_reg(session: &mut Session, reg: usize, bytes: &[u8]) {
+    let cpu = session.cpu_mut();
+    let wide = u16::from_le_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)]);
+    let (timer, sound) = cpu.dump_clock();
+    match reg {
+        0..=15 => cpu.set_register(reg, bytes[0]),
+        REG_I => cpu.set_large_register(wide),
+        REG_PC => cpu.set_pc(wide),
+        REG_DT => cpu.set_clock(bytes[0], sound),
+        REG_ST => cpu.set_clock(timer, bytes[0]),
+        // The stack pointer is read-only
+        _ => (),
+    }
+}
+
+///What the connection loop should do after a packet
+enum Reply {
+    Packet(String),
+    Resume,         //Run the session and answer with a stop reply
+    Close,
+}
+
+///GDB remote serial protocol server driving a `Session`
+pub struct GdbStub {
+    session: Session,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(session: Session) -> Self {
+        Self { session, no_ack: false }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn into_session(self) -> Session {
+        self.session
+    }
+
+    ///Accepts one client on `listener` and serves it until it detaches or disconnects
+    pub fn serve(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        loop {
+            let Some(packet) = read_packet(&mut stream, self.no_ack)? else {
+                return Ok(());
+            };
+            let reply = match packet.as_str() {
+                // A bare interrupt while stopped is answered with the current state
+                "\x03" => Reply::Packet("S02".to_owned()),
+                _ => self.handle(&packet),
+            };
+            match reply {
+                Reply::Packet(data) => write_packet(&mut stream, &data)?,
+                Reply::Resume => {
+                    let stop = self.run(&mut stream)?;
+                    write_packet(&mut stream, &stop)?;
+                }
+                Reply::Close => {
+                    write_packet(&mut stream, "OK")?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let ok = || Reply::Packet("OK".to_owned());
+        let err = || Reply::Packet("E01".to_owned());
+        let Some(cmd) = packet.get(..1) else { return Reply::Packet(String::new()) };
+        let args = &packet[1..];
+        match cmd {
+            "?" => Reply::Packet("S05".to_owned()),
+            "g" => Reply::Packet((0..REG_COUNT).map(|r| to_hex(&read_reg(&self.session, r))).collect()),
+            "G" => {
+                let Some(bytes) = from_hex(args) else { return err() };
+                let mut offset = 0;
+                for reg in 0..REG_COUNT {
+                    let size = reg_size(reg);
+                    if offset + size > bytes.len() {
+                        break;
+                    }
+                    write_reg(&mut self.session, reg, &bytes[offset..offset + size]);
+                    offset += size;
+                }
+                ok()
+            }
+            "p" => match parse_hex(args).filter(|r| *r < REG_COUNT) {
+                Some(reg) => Reply::Packet(to_hex(&read_reg(&self.session, reg))),
+                None => err(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, v)| Some((parse_hex(r)?, from_hex(v)?)));
+                match parsed {
+                    Some((reg, bytes)) if reg < REG_COUNT && bytes.len() == reg_size(reg) => {
+                        write_reg(&mut self.session, reg, &bytes);
+                        ok()
+                    }
+                    _ => err(),
+                }
+            }
+            "m" => {
+                let range = args.split_once(',').and_then(|(a, l)| Some((parse_hex(a)?, parse_hex(l)?)));
+                let memory = self.session.cpu().dump_memory();
+                match range {
+                    Some((addr, len)) if addr.checked_add(len).is_some_and(|end| end <= memory.len()) => Reply::Packet(to_hex(&memory[addr..addr + len])),
+                    _ => err(),
+                }
+            }
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = range.split_once(',')?;
+                    Some((parse_hex(addr)?, parse_hex(len)?, from_hex(data)?))
+                });
+                match parsed {
+                    Some((addr, len, bytes))
+                        if bytes.len() == len && addr.checked_add(len).is_some_and(|end| end <= self.session.cpu().dump_memory().len()) =>
+                    {
+                        for (i, byte) in bytes.into_iter().enumerate() {
+                            self.session.cpu_mut().poke_memory((addr + i) as u16, byte);
+                        }
+                        ok()
+                    }
+                    _ => err(),
+                }
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let (Some("0"), Some(addr)) = (fields.next(), fields.next().and_then(parse_hex)) else {
+                    // Only software breakpoints are supported
+                    return Reply::Packet(String::new());
+                };
+                let addr = addr as u16;
+                let breakpoints = self.session.breakpoints_mut();
+                if cmd == "Z" {
+                    if !breakpoints.has_address(addr) {
+                        breakpoints.add(BreakKind::Address(addr), None, false);
+                    }
+                } else {
+                    breakpoints.remove_address(addr);
+                }
+                ok()
+            }
+            "s" | "c" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.session.cpu_mut().set_pc(addr as u16);
+                }
+                let command = if cmd == "s" { Command::Step(1) } else { Command::Continue };
+                self.session.execute(&command);
+                Reply::Resume
+            }
+            "H" | "T" => ok(),
+            "D" | "k" => Reply::Close,
+            "q" => self.query(packet),
+            "Q" if packet == "QStartNoAckMode" => {
+                self.no_ack = true;
+                ok()
+            }
+            _ => Reply::Packet(String::new()),
+        }
+    }
+
+    fn query(&self, packet: &str) -> Reply {
+        if packet.starts_with("qSupported") {
+            return Reply::Packet("PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_owned());
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',').and_then(|(o, l)| Some((parse_hex(o)?, parse_hex(l)?))) else {
+                return Reply::Packet("E01".to_owned());
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = offset.saturating_add(len).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            return Reply::Packet(format!("{}{}", marker, &TARGET_XML[start..end]));
+        }
+        let reply = match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        };
+        Reply::Packet(reply.to_owned())
+    }
+
+    ///Runs until the session stops or the client interrupts, returning the stop reply
+    fn run(&mut self, stream: &mut TcpStream) -> io::Result<String> {
+        let mut since_poll = 0;
+        loop {
+            if let Some(stop) = self.session.tick().stop {
+                return Ok(match stop {
+                    StopReason::Breakpoint(_) => "T05swbreak:;".to_owned(),
+                    StopReason::Halted => "W00".to_owned(),
+                    StopReason::Fault(_) => "S0B".to_owned(),
+                    _ => "S05".to_owned(),
+                });
+            }
+            since_poll += 1;
+            if since_poll < POLL_INTERVAL {
+                continue;
+            }
+            since_poll = 0;
+            stream.set_nonblocking(true)?;
+            let mut byte = [0u8];
+            let interrupted = match stream.read(&mut byte) {
+                Ok(1) => byte[0] == 0x03,
+                Ok(_) => return Err(ErrorKind::UnexpectedEof.into()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(e) => return Err(e),
+            };
+            stream.set_nonblocking(false)?;
+            if interrupted {
+                self.session.execute(&Command::Pause);
+                return Ok("S02".to_owned());
+            }
+        }
+    }
+}
+
+///Reads the next packet, acknowledging it. `None` when the client hangs up
+fn read_packet(stream: &mut TcpStream, no_ack: bool) -> io::Result<Option<String>> {
+    let mut byte = [0u8];
+    // A packet with a bad checksum is nacked and the client sends it again
+    loop {
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Ok(Some("\x03".to_owned())),
+                // Acks from the client and noise between packets
+                _ => continue,
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !no_ack {
+            stream.write_all(if expected == Some(actual) { b"+" } else { b"-" })?;
+        }
+        if expected == Some(actual) {
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+        }
+    }
+}
+
+///Undoes the `}` escaping used in binary packet data
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+pub fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stream, "${}#{:02x}", data, checksum)?;
+    stream.flush()
+}
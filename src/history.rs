@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Chip8;
+
+///Instructions between checkpoints, the most that has to be replayed to reach any cycle
+pub const CHECKPOINT_INTERVAL: u64 = 256;
+///Checkpoints kept before the oldest is dropped, bounds how far back history reaches
+pub const MAX_CHECKPOINTS: usize = 1024;
+///Executed instructions kept for the timeline
+pub const TIMELINE_LEN: usize = 4096;
+
+///One executed instruction in the timeline
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct TimelineEntry {
+    pub cycle: u64,     //Instructions executed before this one
+    pub pc: u16,
+    pub op_code: u16,
+}
+
+///Recorded execution that can be replayed to any earlier cycle.
+///
+///A cycle is the number of instructions executed so far. Checkpoints are full `Chip8` clones,
+///which carry the RNG state, and key presses are logged with the cycle they arrived at, so
+///replaying from a checkpoint reproduces the original run exactly. Recording can continue past
+///the current cycle after a rewind, that future stays valid until something diverges from it.
+#[derive(Clone)]
+pub struct History {
+    checkpoints: VecDeque<(u64, Chip8)>,
+    inputs: Vec<(u64, u16)>,    //Key mask set before the instruction at that cycle
+    timeline: VecDeque<TimelineEntry>,
+    dirty: bool,                //The machine was changed from outside since the last checkpoint
+}
+
+impl History {
+    pub fn new(cpu: &Chip8) -> Self {
+        Self {
+            checkpoints: VecDeque::from([(0, cpu.clone())]),
+            inputs: Vec::new(),
+            timeline: VecDeque::new(),
+            dirty: false,
+        }
+    }
+
+    ///Earliest cycle that can still be restored
+    pub fn earliest(&self) -> u64 {
+        self.checkpoints.front().map_or(0, |(cycle, _)| *cycle)
+    }
+
+    ///Latest cycle recorded, may be ahead of the current one after a rewind
+    pub fn latest(&self) -> u64 {
+        let checkpoint = self.checkpoints.back().map_or(0, |(cycle, _)| *cycle);
+        let executed = self.timeline.back().map_or(0, |e| e.cycle + 1);
+        checkpoint.max(executed)
+    }
+
+    pub fn timeline(&self) -> impl DoubleEndedIterator<Item = &TimelineEntry> {
+        self.timeline.iter()
+    }
+
+    ///Forgets everything recorded after `cycle`
+    fn truncate(&mut self, cycle: u64) {
+        while self.checkpoints.len() > 1 && self.checkpoints.back().is_some_and(|(c, _)| *c > cycle) {
+            self.checkpoints.pop_back();
+        }
+        self.inputs.retain(|(c, _)| *c <= cycle);
+        while self.timeline.back().is_some_and(|e| e.cycle >= cycle) {
+            self.timeline.pop_back();
+        }
+    }
+
+    ///Marks the machine as edited from outside, the recorded future no longer applies
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
+    ///Logs a key change at `cycle`, dropping any recorded future
+    pub fn set_keys(&mut self, cycle: u64, keys: u16) {
+        self.truncate(cycle);
+        self.inputs.retain(|(c, _)| *c < cycle);
+        self.inputs.push((cycle, keys));
+    }
+
+    fn checkpoint(&mut self, cycle: u64, cpu: &Chip8) {
+        self.truncate(cycle);
+        if self.checkpoints.back().is_some_and(|(c, _)| *c == cycle) {
+            self.checkpoints.pop_back();
+        }
+        self.checkpoints.push_back((cycle, cpu.clone()));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+            let earliest = self.earliest();
+            self.inputs.retain(|(c, _)| *c >= earliest);
+        }
+    }
+
+    ///Checkpoints an edited machine so later replays include the edit
+    pub fn sync(&mut self, cycle: u64, cpu: &Chip8) {
+        if std::mem::take(&mut self.dirty) {
+            self.checkpoint(cycle, cpu);
+        }
+    }
+
+    ///Called before the instruction at `cycle` runs. Applies recorded input and either
+    ///takes a checkpoint or, when re-running recorded history, restores the one saved there
+    pub fn before_cycle(&mut self, cycle: u64, cpu: &mut Chip8) {
+        self.sync(cycle, cpu);
+        match self.checkpoints.binary_search_by_key(&cycle, |(c, _)| *c) {
+            Ok(index) => *cpu = self.checkpoints[index].1.clone(),
+            Err(_) if cycle.is_multiple_of(CHECKPOINT_INTERVAL) => self.checkpoint(cycle, cpu),
+            Err(_) => (),
+        }
+        if let Some(keys) = self.keys_at(cycle) {
+            cpu.set_keys(keys);
+        }
+    }
+
+    ///Called after the instruction at `cycle` ran
+    pub fn after_cycle(&mut self, cycle: u64, pc: u16, op_code: u16) {
+        if self.timeline.back().is_some_and(|e| e.cycle >= cycle) {
+            // Re-running recorded history, the entry is already there
+            return;
+        }
+        self.timeline.push_back(TimelineEntry { cycle, pc, op_code });
+        if self.timeline.len() > TIMELINE_LEN {
+            self.timeline.pop_front();
+        }
+    }
+
+    fn keys_at(&self, cycle: u64) -> Option<u16> {
+        // Sorted by cycle, one entry per cycle
+        let index = self.inputs.binary_search_by_key(&cycle, |(c, _)| *c).ok()?;
+        Some(self.inputs[index].1)
+    }
+
+    ///Runs `cpu` forward from `from` towards `to`, calling `visit` with each cycle reached
+    ///including `from`. Stops early when `visit` returns false or the program can't continue
+    fn replay(&self, cpu: &mut Chip8, from: u64, to: u64, mut visit: impl FnMut(u64, &Chip8) -> bool) -> u64 {
+        let mut cycle = from;
+        loop {
+            if let Some(keys) = self.keys_at(cycle) {
+                cpu.set_keys(keys);
+            }
+            if !visit(cycle, cpu) || cycle >= to || !matches!(cpu.try_cycle(), Ok(Some(_))) {
+                return cycle;
+            }
+            cycle += 1;
+        }
+    }
+
+    ///Rebuilds the machine as it was at `cycle`, clamped to the recorded range
+    pub fn restore(&self, cycle: u64) -> (u64, Chip8) {
+        let (start, saved) = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(c, _)| *c <= cycle)
+            .or(self.checkpoints.front())
+            .expect("history always has a checkpoint");
+        let mut cpu = saved.clone();
+        let reached = self.replay(&mut cpu, *start, cycle.max(*start), |_, _| true);
+        (reached, cpu)
+    }
+
+    ///Latest cycle before `before` where `found` holds. Replays one checkpoint interval at a
+    ///time from the newest backwards, each one also visiting the first cycle of the next
+    pub fn search_back(&self, before: u64, mut found: impl FnMut(u64, &Chip8) -> bool) -> Option<u64> {
+        let mut end = before;
+        for (start, saved) in self.checkpoints.iter().rev().filter(|(c, _)| *c < before) {
+            let mut cpu = saved.clone();
+            let mut last = None;
+            self.replay(&mut cpu, *start, end.min(before - 1), |cycle, cpu| {
+                if found(cycle, cpu) {
+                    last = Some(cycle);
+                }
+                true
+            });
+            if last.is_some() {
+                return last;
+            }
+            end = *start;
+        }
+        None
+    }
+}
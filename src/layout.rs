@@ -0,0 +1,231 @@
+use std::{fs::File, process::Stdio};
+
+use crate::display::{HEIGHT, WIDTH};
+
+///Size assumed when the terminal can't be asked, large enough for every panel
+pub const DEFAULT_SIZE: (usize, usize) = (200, 80);
+///First column right of the game screen
+const RIGHT_COLUMN: usize = WIDTH + 2;
+///First row below the screen, status line and prompt
+const BELOW_ROW: usize = HEIGHT + 6;
+
+///Debugger panels that can be laid out
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Panel {
+    Registers,
+    Stack,
+    Clock,
+    Code,
+    Memory,
+    History,
+    Profile,
+    Sprites,
+    Breakpoints,
+}
+
+impl Panel {
+    ///Every panel in layout order
+    pub const ALL: [Panel; 9] = [
+        Panel::Registers,
+        Panel::Stack,
+        Panel::Clock,
+        Panel::Code,
+        Panel::Memory,
+        Panel::History,
+        Panel::Profile,
+        Panel::Sprites,
+        Panel::Breakpoints,
+    ];
+    ///Panels shown without asking for them
+    pub const DEFAULT: [Panel; 7] =
+        [Panel::Registers, Panel::Stack, Panel::Clock, Panel::Code, Panel::Memory, Panel::History, Panel::Breakpoints];
+
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Panel::Registers => "Registers",
+            Panel::Stack => "Stack",
+            Panel::Clock => "Clocks",
+            Panel::Code => "Code",
+            Panel::Memory => "Memory",
+            Panel::History => "History",
+            Panel::Profile => "Profile",
+            Panel::Sprites => "Sprites",
+            Panel::Breakpoints => "Breakpoints",
+        }
+    }
+
+    ///Smallest width and height the panel is readable at, and the most rows it can use
+    fn size(self) -> (usize, usize, usize) {
+        match self {
+            Panel::Registers => (16, 19, 19),
+            Panel::Stack => (20, 2, 49),
+            Panel::Clock => (12, 3, 3),
+            Panel::Code => (44, 4, 31),
+            Panel::Memory => (73, 5, 17),
+            Panel::History => (40, 4, 16),
+            Panel::Profile => (48, 4, usize::MAX),
+            Panel::Sprites => (35, 8, 40),
+            Panel::Breakpoints => (40, 2, usize::MAX),
+        }
+    }
+}
+
+///Screen area in terminal cells, 1-based like ANSI cursor positions
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    fn fits(&self, panel: Panel) -> bool {
+        let (width, height, _) = panel.size();
+        self.width >= width && self.height >= height
+    }
+}
+
+///Where every panel goes for one terminal size.
+///
+///Panels flow left to right in bands right of and below the game screen. Panels that don't
+///fit share the largest placed area as tabs, any that don't fit there either are collapsed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Layout {
+    pub size: (usize, usize),
+    pub status: (usize, usize),     //Status line, the message and prompt go on the rows below
+    panels: Vec<(Panel, Rect)>,
+    pub tabs: Vec<Panel>,           //Panels sharing `tab_bar`, only the active one is placed
+    pub tab_bar: Option<Rect>,
+    pub collapsed: Vec<Panel>,
+}
+
+impl Layout {
+    ///Lays out `shown` for a `cols` x `rows` terminal, none leaves only the game screen.
+    ///`tab` picks which of the tabbed panels is shown
+    pub fn compute(cols: usize, rows: usize, shown: &[Panel], tab: usize) -> Layout {
+        let mut layout = Layout {
+            size: (cols, rows),
+            status: (1, HEIGHT + 2),
+            panels: Vec::new(),
+            tabs: Vec::new(),
+            tab_bar: None,
+            collapsed: Vec::new(),
+        };
+        let all = Panel::ALL;
+        let below_height = rows.saturating_sub(BELOW_ROW - 1);
+        let bands = [
+            (Rect { x: RIGHT_COLUMN, y: 1, width: cols.saturating_sub(RIGHT_COLUMN - 1), height: HEIGHT }, &all[..4]),
+            (Rect { x: 1, y: BELOW_ROW, width: cols, height: below_height.min(17) }, &all[4..6]),
+            (Rect { x: 1, y: BELOW_ROW + 18, width: cols, height: below_height.saturating_sub(18) }, &all[6..]),
+        ];
+        let mut overflow = Vec::new();
+        for (band, panels) in bands {
+            let panels: Vec<Panel> = panels.iter().copied().filter(|p| shown.contains(p)).collect();
+            let mut x = band.x;
+            for &panel in &panels {
+                let (width, _, max_height) = panel.size();
+                let free = Rect { x, y: band.y, width: (band.x + band.width).saturating_sub(x), height: band.height };
+                if !free.fits(panel) {
+                    overflow.push(panel);
+                    continue;
+                }
+                // The last panel of a band takes the rest of its width
+                let width = if Some(&panel) == panels.last() { free.width } else { width };
+                layout.panels.push((panel, Rect { width, height: band.height.min(max_height), ..free }));
+                x += width + 1;
+            }
+        }
+        if overflow.is_empty() {
+            return layout;
+        }
+        // Overflowing panels take turns in the largest placed area, below a one row tab bar. The
+        // area's own panel becomes a tab too, so it has to fit there as well
+        let below_bar = |slot: Rect| Rect { y: slot.y + 1, height: slot.height.saturating_sub(1), ..slot };
+        let owner = (0..layout.panels.len())
+            .filter(|i| below_bar(layout.panels[*i].1).fits(layout.panels[*i].0))
+            .max_by_key(|i| layout.panels[*i].1.area());
+        let Some(index) = owner else {
+            layout.collapsed = overflow;
+            return layout;
+        };
+        let (owner, slot) = layout.panels.remove(index);
+        let area = below_bar(slot);
+        layout.tabs.push(owner);
+        for panel in overflow {
+            if area.fits(panel) {
+                layout.tabs.push(panel);
+            } else {
+                layout.collapsed.push(panel);
+            }
+        }
+        layout.tabs.sort_by_key(|p| all.iter().position(|a| a == p));
+        layout.tab_bar = Some(Rect { height: 1, ..slot });
+        layout.panels.push((layout.tabs[tab % layout.tabs.len()], area));
+        layout
+    }
+
+    pub fn panels(&self) -> &[(Panel, Rect)] {
+        &self.panels
+    }
+
+    ///Where a panel is drawn, `None` when it is hidden
+    pub fn rect(&self, panel: Panel) -> Option<Rect> {
+        self.panels.iter().find(|(p, _)| *p == panel).map(|(_, rect)| *rect)
+    }
+
+    ///Tab currently shown, if panels are tabbed
+    pub fn active_tab(&self) -> Option<Panel> {
+        self.tabs.iter().copied().find(|tab| self.rect(*tab).is_some())
+    }
+}
+
+///Runs `stty` on the terminal and returns what it printed, `None` without a terminal
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = File::open("/dev/tty").ok()?;
+    let output = std::process::Command::new("stty").args(args).stdin(tty).stderr(Stdio::null()).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+///Asks the terminal for its size as (columns, rows)
+pub fn terminal_size() -> Option<(usize, usize)> {
+    let text = stty(&["size"])?;
+    let (rows, cols) = text.split_once(' ')?;
+    Some((cols.parse().ok()?, rows.parse().ok()?))
+}
+
+///Terminal handing over every key as it is pressed, without echoing it. Ctrl-C arrives as a key
+///instead of a signal so the caller can quit through its normal cleanup. The old settings come
+///back when this is dropped, and from the panic hook if the program dies first
+pub struct RawKeys {
+    saved: String,
+}
+
+impl RawKeys {
+    pub fn enable() -> Option<RawKeys> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+        let restore = saved.clone();
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal(&restore);
+            previous(info);
+        }));
+        Some(RawKeys { saved })
+    }
+}
+
+impl Drop for RawKeys {
+    fn drop(&mut self) {
+        restore_terminal(&self.saved);
+    }
+}
+
+fn restore_terminal(saved: &str) {
+    stty(&[saved]);
+}
@@ -1,6 +1,27 @@
 pub mod cpu;
 pub mod parser;
 pub mod display;
+pub mod theme;
+pub mod png;
 pub mod debugger;
+pub mod symbols;
+pub mod analysis;
+pub mod lint;
+pub mod writer;
+pub mod session;
+pub mod expr;
+pub mod breakpoints;
+pub mod watchpoints;
+pub mod gdb;
+pub mod trace;
+pub mod history;
+pub mod script;
+pub mod layout;
+pub mod screen;
+pub mod profile;
+pub mod coverage;
+pub mod json;
+pub mod dap;
 mod fastrand;
+#[allow(clippy::module_inception)]
 mod tests;
@@ -0,0 +1,288 @@
+use std::{collections::{BTreeMap, VecDeque}, fmt};
+
+use crate::{
+    analysis::{ControlFlowGraph, EdgeKind},
+    parser::{DataType, OpCode, OpCodeIdentity},
+    symbols::SymbolTable,
+};
+
+///Size of the return stack in `cpu::Stack`
+pub const STACK_DEPTH: usize = 48;
+///Memory below this address belongs to the interpreter
+pub const INTERPRETER_END: u16 = 0x200;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LintKind {
+    CallDepth,          //Calls nest deeper than the stack allows
+    ReturnOutsideSub,   //RetSub reachable from the main program
+    UnsetAddrReg,       //DXYN with I never set
+    BadJumpTarget,      //Jump or call into data or past the program
+    InterpreterWrite,   //FX55/FX65/FX33 touching the interpreter area
+    QuirkDependent,     //Behaviour differs between interpreters
+}
+
+///A finding at a program address
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub addr: u16,
+    pub severity: Severity,
+    pub kind: LintKind,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn describe(&self, symbols: Option<&SymbolTable>) -> String {
+        let addr = match symbols {
+            Some(symbols) => symbols.describe(self.addr),
+            None => format!("{:#05X}", self.addr),
+        };
+        let severity = match self.severity {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        format!("{}: {}: {}", addr, severity, self.message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.describe(None))
+    }
+}
+
+///Runs every lint over `program`, sorted by address
+pub fn lint(program: &[OpCode], symbols: Option<&SymbolTable>) -> Vec<Diagnostic> {
+    let cfg = ControlFlowGraph::build(program);
+    let mut out = Vec::new();
+    check_call_depth(&cfg, &mut out);
+    check_stray_returns(program, &cfg, &mut out);
+    check_unset_addr_reg(program, &cfg, &mut out);
+    check_jump_targets(program, symbols, &mut out);
+    check_interpreter_writes(program, &cfg, &mut out);
+    check_quirks(program, &mut out);
+    out.sort_by_key(|d| (d.addr, d.severity));
+    out.dedup();
+    out
+}
+
+fn push(out: &mut Vec<Diagnostic>, addr: u16, severity: Severity, kind: LintKind, message: String) {
+    out.push(Diagnostic { addr, severity, kind, message });
+}
+
+///Call sites made from the blocks of each subroutine, keyed by the entry (0 for main)
+fn call_graph(cfg: &ControlFlowGraph) -> BTreeMap<u16, Vec<(u16, u16)>> {
+    let mut graph = BTreeMap::new();
+    let entries = std::iter::once(0).chain(cfg.subroutines.keys().copied());
+    for entry in entries {
+        let mut calls = Vec::new();
+        for start in cfg.walk(entry, false) {
+            let block = &cfg.blocks[&start];
+            for edge in block.successors.iter().filter(|e| e.kind == EdgeKind::Call) {
+                calls.push((block.end - 1, edge.target));
+            }
+        }
+        graph.insert(entry, calls);
+    }
+    graph
+}
+
+///Deepest chain of nested calls starting in `entry`, warning about recursive calls on the way
+fn nesting(
+    entry: u16,
+    graph: &BTreeMap<u16, Vec<(u16, u16)>>,
+    memo: &mut BTreeMap<u16, usize>,
+    active: &mut Vec<u16>,
+    out: &mut Vec<Diagnostic>,
+) -> usize {
+    if let Some(depth) = memo.get(&entry) {
+        return *depth;
+    }
+    active.push(entry);
+    let mut deepest = 0;
+    for &(site, target) in graph.get(&entry).into_iter().flatten() {
+        if active.contains(&target) {
+            push(out, site, Severity::Warning, LintKind::CallDepth,
+                format!("recursive call to {:#05X} can overflow the stack", target));
+            continue;
+        }
+        deepest = deepest.max(1 + nesting(target, graph, memo, active, out));
+    }
+    active.pop();
+    memo.insert(entry, deepest);
+    deepest
+}
+
+fn check_call_depth(cfg: &ControlFlowGraph, out: &mut Vec<Diagnostic>) {
+    let graph = call_graph(cfg);
+    let mut memo = BTreeMap::new();
+    for &(site, target) in graph.get(&0).into_iter().flatten() {
+        let depth = 1 + nesting(target, &graph, &mut memo, &mut vec![0], out);
+        if depth > STACK_DEPTH {
+            push(out, site, Severity::Error, LintKind::CallDepth,
+                format!("calls nest {} deep, the stack holds {}", depth, STACK_DEPTH));
+        }
+    }
+}
+
+fn check_stray_returns(program: &[OpCode], cfg: &ControlFlowGraph, out: &mut Vec<Diagnostic>) {
+    if program.is_empty() {
+        return;
+    }
+    for start in cfg.walk(0, false) {
+        let end = cfg.blocks[&start].end - 1;
+        if program[end as usize].oc_id == OpCodeIdentity::RetSub {
+            push(out, end, Severity::Error, LintKind::ReturnOutsideSub,
+                "return reachable outside any subroutine".to_owned());
+        }
+    }
+}
+
+fn sets_addr_reg(oc: &OpCode) -> bool {
+    matches!(oc.oc_id, OpCodeIdentity::SetAddrRegC | OpCodeIdentity::SetAddrRegSpriteR)
+}
+
+fn check_unset_addr_reg(program: &[OpCode], cfg: &ControlFlowGraph, out: &mut Vec<Diagnostic>) {
+    if program.is_empty() {
+        return;
+    }
+    let block_sets = |start: u16| {
+        let block = &cfg.blocks[&start];
+        (block.start..block.end).any(|addr| sets_addr_reg(&program[addr as usize]))
+    };
+    let sub_sets: BTreeMap<u16, bool> = cfg
+        .subroutines
+        .iter()
+        .map(|(entry, sub)| (*entry, sub.blocks.iter().any(|b| block_sets(*b))))
+        .collect();
+    // May-analysis: has any path to the block start passed an instruction setting I?
+    let mut state: BTreeMap<u16, bool> = BTreeMap::new();
+    let mut queue = VecDeque::from([(0u16, false)]);
+    while let Some((start, set)) = queue.pop_front() {
+        if !cfg.blocks.contains_key(&start) {
+            continue;
+        }
+        match state.get(&start) {
+            Some(&old) if old || !set => continue,
+            _ => {
+                state.insert(start, set);
+            }
+        }
+        let block = &cfg.blocks[&start];
+        let set_out = set || block_sets(start);
+        let callee_sets = block
+            .successors
+            .iter()
+            .any(|e| e.kind == EdgeKind::Call && sub_sets.get(&e.target).copied().unwrap_or(false));
+        for edge in &block.successors {
+            let carried = if edge.kind == EdgeKind::Fallthrough { set_out || callee_sets } else { set_out };
+            queue.push_back((edge.target, carried));
+        }
+    }
+    for (start, set) in state {
+        let block = &cfg.blocks[&start];
+        let mut set = set;
+        for addr in block.start..block.end {
+            let oc = &program[addr as usize];
+            if oc.oc_id == OpCodeIdentity::DrawDispRRC && !set {
+                push(out, addr, Severity::Warning, LintKind::UnsetAddrReg,
+                    "sprite drawn from I but I is never set before this point".to_owned());
+            }
+            set |= sets_addr_reg(oc);
+        }
+    }
+}
+
+fn check_jump_targets(program: &[OpCode], symbols: Option<&SymbolTable>, out: &mut Vec<Diagnostic>) {
+    // Addresses index whole instructions, so a target can never land mid-instruction
+    for (addr, oc) in program.iter().enumerate() {
+        let addr = addr as u16;
+        if !matches!(oc.oc_id, OpCodeIdentity::JumpAddr | OpCodeIdentity::CallSub) {
+            continue;
+        }
+        let DataType::NNN { address } = oc.get_data() else {
+            continue;
+        };
+        if address as usize >= program.len() {
+            push(out, addr, Severity::Error, LintKind::BadJumpTarget,
+                format!("target {:#05X} is past the end of the program", address));
+        } else if let Some(region) = symbols.and_then(|s| s.data_region(address)) {
+            let name = region.name.as_deref().unwrap_or("data");
+            push(out, addr, Severity::Error, LintKind::BadJumpTarget,
+                format!("target {:#05X} is inside {}", address, name));
+        }
+    }
+}
+
+fn check_interpreter_writes(program: &[OpCode], cfg: &ControlFlowGraph, out: &mut Vec<Diagnostic>) {
+    // Constant propagation of I and the registers inside each block
+    for block in cfg.blocks.values() {
+        let mut regs: [Option<u8>; 16] = [None; 16];
+        let mut addr_reg: Option<u16> = if block.start == 0 { Some(0) } else { None };
+        if block.start == 0 {
+            regs = [Some(0); 16];
+        }
+        for addr in block.start..block.end {
+            let oc = &program[addr as usize];
+            let data = oc.get_data();
+            match (oc.oc_id, data) {
+                (OpCodeIdentity::SetAddrRegC, DataType::NNN { address }) => addr_reg = Some(address),
+                (OpCodeIdentity::AddAddrRegR, DataType::X { x }) => {
+                    addr_reg = addr_reg.zip(regs[x as usize]).map(|(i, v)| i + v as u16);
+                }
+                (OpCodeIdentity::SetAddrRegSpriteR, _) => addr_reg = None,
+                (OpCodeIdentity::SetRC, DataType::XNN { x, constant }) => regs[x as usize] = Some(constant),
+                (OpCodeIdentity::AddNcRC, DataType::XNN { x, constant }) => {
+                    regs[x as usize] = regs[x as usize].map(|v| v.wrapping_add(constant));
+                }
+                (OpCodeIdentity::SetRR, DataType::XY { x, y }) => regs[x as usize] = regs[y as usize],
+                (OpCodeIdentity::DumpRegsToMemR | OpCodeIdentity::LoadRegsFromMemR | OpCodeIdentity::SetBcdR, DataType::X { x }) => {
+                    if let Some(i) = addr_reg.filter(|i| *i < INTERPRETER_END) {
+                        let verb = if oc.oc_id == OpCodeIdentity::LoadRegsFromMemR { "reads" } else { "writes" };
+                        push(out, addr, Severity::Warning, LintKind::InterpreterWrite,
+                            format!("{} {:#05X} inside the interpreter area", verb, i));
+                    }
+                    if oc.oc_id == OpCodeIdentity::LoadRegsFromMemR {
+                        for reg in regs.iter_mut().take(x as usize + 1) {
+                            *reg = None;
+                        }
+                    }
+                }
+                (_, DataType::X { x } | DataType::XY { x, .. } | DataType::XNN { x, .. }) => {
+                    if !matches!(oc.oc_id, OpCodeIdentity::SetDelayR | OpCodeIdentity::SetSoundR | OpCodeIdentity::AddAddrRegR)
+                        && !crate::analysis::is_skip(oc.oc_id)
+                    {
+                        regs[x as usize] = None;
+                    }
+                    if matches!(oc.oc_id, OpCodeIdentity::AddRR | OpCodeIdentity::SubRRR | OpCodeIdentity::SubLRR
+                        | OpCodeIdentity::RshiftR | OpCodeIdentity::LshiftR)
+                    {
+                        regs[0xF] = None;
+                    }
+                }
+                (OpCodeIdentity::DrawDispRRC, _) => regs[0xF] = None,
+                _ => (),
+            }
+        }
+    }
+}
+
+fn check_quirks(program: &[OpCode], out: &mut Vec<Diagnostic>) {
+    for (addr, oc) in program.iter().enumerate() {
+        let message = match oc.oc_id {
+            OpCodeIdentity::RshiftR | OpCodeIdentity::LshiftR => "shift source is VX or VY depending on the interpreter",
+            OpCodeIdentity::DumpRegsToMemR | OpCodeIdentity::LoadRegsFromMemR => "whether I is incremented depends on the interpreter",
+            OpCodeIdentity::JumpAddrCR => "offset register is V0 or VX depending on the interpreter",
+            OpCodeIdentity::OrRR | OpCodeIdentity::AndRR | OpCodeIdentity::XorRR => "whether VF is reset depends on the interpreter",
+            _ => continue,
+        };
+        push(out, addr as u16, Severity::Info, LintKind::QuirkDependent, message.to_owned());
+    }
+}
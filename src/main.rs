@@ -1,41 +1,397 @@
+use std::{
+    collections::VecDeque,
+    io::{BufReader, IsTerminal, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
 
-use dexterws_skye_emulator::{cpu::Chip8, debugger::{DebugLocations, Debugger}, display::{Display, WIDTH}};
+use dexterws_skye_emulator::{
+    analysis::ControlFlowGraph,
+    coverage::Coverage,
+    dap::DapServer,
+    debugger::Debugger,
+    display::{backend_from_name, save_screenshot, screenshot_path, DisplayBackend, LORES, SCREENSHOT_SCALE},
+    gdb::GdbStub,
+    layout::{terminal_size, RawKeys, Panel, DEFAULT_SIZE},
+    lint::lint,
+    parser::OpCodeType,
+    profile::Profiler,
+    screen::Screen,
+    script::ScriptRunner,
+    session::{Command, Session, StopReason},
+    theme::{ColorDepth, Theme},
+    symbols::SymbolTable,
+    trace::{TraceFilter, Tracer},
+    writer::{self, HexStyle},
+};
 
 const CLOCK_CYCLE: u64 = 500;
 const SLEEP_TIME: u64 = 1000 / CLOCK_CYCLE;
+///How often the terminal is asked for its size
+const RESIZE_POLL: Duration = Duration::from_millis(500);
+///Saves a screenshot when pressed during a run without the debugger
+const SCREENSHOT_KEY: u8 = b'p';
+///Ctrl-C, which reaches the hotkey reader as a byte while raw keys are on
+const QUIT_KEY: u8 = 0x03;
+
+#[derive(Default)]
+struct Options {
+    file: String,
+    dot_path: Option<String>,
+    lint: bool,
+    convert_path: Option<String>,
+    debug: bool,
+    gdb_port: Option<u16>,
+    trace_path: Option<String>,
+    trace_ranges: Vec<String>,
+    trace_types: Vec<String>,
+    seed: Option<u64>,
+    script_path: Option<String>,
+    profile_path: Option<String>,
+    coverage_path: Option<String>,
+    dap: bool,
+    display: Option<String>,
+    theme: Option<String>,
+    colors: Option<ColorDepth>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dot" => options.dot_path = Some(args.next().expect("--dot needs a path")),
+            "--lint" => options.lint = true,
+            "--debug" => options.debug = true,
+            "--dap" => options.dap = true,
+            "--gdb" => options.gdb_port = Some(args.next().and_then(|p| p.parse().ok()).expect("--gdb needs a port")),
+            "--trace" => options.trace_path = Some(args.next().expect("--trace needs a path")),
+            "--trace-range" => options.trace_ranges.push(args.next().expect("--trace-range needs a range")),
+            "--trace-type" => options.trace_types.push(args.next().expect("--trace-type needs an op code type")),
+            "--seed" => options.seed = Some(args.next().and_then(|s| s.parse().ok()).expect("--seed needs a number")),
+            "--profile" => options.profile_path = Some(args.next().expect("--profile needs a path")),
+            "--coverage" => options.coverage_path = Some(args.next().expect("--coverage needs a path")),
+            "--display" => options.display = Some(args.next().expect("--display needs ansi[:block|half|braille|ascii], null, pbm:<dir>, ppm:<dir> or png:<dir>")),
+            "--theme" => options.theme = Some(args.next().expect("--theme needs a theme name or file")),
+            "--colors" => options.colors = Some(args.next().and_then(|c| ColorDepth::from_name(&c)).expect("--colors needs 256 or truecolor")),
+            "--script" => options.script_path = Some(args.next().expect("--script needs a path or -")),
+            "--convert" => options.convert_path = Some(args.next().expect("--convert needs a path")),
+            _ => options.file = arg,
+        }
+    }
+    // The DAP client names the program in its launch request
+    if options.file.is_empty() && !options.dap {
+        panic!("No file provided");
+    }
+    options
+}
 
 fn main() {
-    let file = std::env::args().nth(1).expect("No file provided");
-    let parsed = dexterws_skye_emulator::parser::parse_file(&file);
-    let mut cpu = Chip8::new(parsed);
-    let debug_locations = DebugLocations {
-        reg_locations: (WIDTH + 2, 1),
-        large_reg_location: (WIDTH + 2, 18),
-        stack_location: (WIDTH + 20, 1),
-        clock_location: (WIDTH + 40, 1),
-        code_locations: (WIDTH + 55, 1),
+    let options = parse_args();
+    if options.dap {
+        // Debug Adapter Protocol on stdin and stdout for editors
+        DapServer::new(std::io::stdout()).serve(std::io::stdin()).expect("Error");
+        return;
+    }
+    let file = options.file;
+    let parsed = dexterws_skye_emulator::parser::parse_file(&file).unwrap_or_else(|err| {
+        eprintln!("Cannot load {}: {}", file, err);
+        std::process::exit(1);
+    });
+    let symbols = SymbolTable::for_program(&file).unwrap_or_else(|err| {
+        eprintln!("Ignoring symbols: {}", err);
+        SymbolTable::new()
+    });
+    if let Some(dot_path) = options.dot_path {
+        // Export the control-flow graph instead of running
+        let cfg = ControlFlowGraph::build(&parsed);
+        std::fs::write(&dot_path, cfg.to_dot(&parsed, Some(&symbols))).expect("Error");
+        return;
+    }
+    if let Some(convert_path) = options.convert_path {
+        // A hex listing converted to hex again keeps its case and line endings
+        let style = if file.ends_with(".ch8") {
+            HexStyle::default()
+        } else {
+            HexStyle::detect(&std::fs::read_to_string(&file).expect("Error"))
+        };
+        writer::save(&convert_path, &parsed, Some(&symbols), style).expect("Error");
+        return;
+    }
+    if options.lint {
+        for diagnostic in lint(&parsed, Some(&symbols)) {
+            println!("{}", diagnostic.describe(Some(&symbols)));
+        }
+        return;
+    }
+    let mut session = match options.seed {
+        Some(seed) => Session::with_seed(parsed, seed),
+        None => Session::new(parsed),
+    };
+    if let Some(trace_path) = &options.trace_path {
+        let mut filter = TraceFilter::default();
+        for range in &options.trace_ranges {
+            filter.ranges.push(TraceFilter::parse_range(range, Some(&symbols)).expect("Error"));
+        }
+        for name in &options.trace_types {
+            filter.types.push(OpCodeType::from_name(name).unwrap_or_else(|| panic!("Unknown op code type {}", name)));
+        }
+        session.set_tracer(Some(Tracer::create(trace_path, filter).expect("Error")));
+    }
+    if options.profile_path.is_some() {
+        session.set_profiler(Some(Profiler::new()));
+    }
+    if options.coverage_path.is_some() {
+        session.set_coverage(Some(Coverage::new()));
+    }
+    // The report is written after the symbols went to the UI
+    let report = Report {
+        profile_path: options.profile_path,
+        coverage_path: options.coverage_path,
+        source_dir: Path::new(&file).parent().unwrap_or(Path::new("")).to_path_buf(),
+        symbols: symbols.clone(),
     };
-    let debugger = Debugger::new(debug_locations);
+    let theme = (options.theme.is_some() || options.colors.is_some()).then(|| {
+        let mut theme = options.theme.as_deref().map_or(Ok(Theme::default()), Theme::load).expect("Error");
+        theme.depth = options.colors.unwrap_or(theme.depth);
+        theme
+    });
+    if let Some(port) = options.gdb_port {
+        // Hand the machine to a remote debugger instead of the terminal
+        let listener = TcpListener::bind(("127.0.0.1", port)).expect("Error");
+        eprintln!("Waiting for gdb on {}", listener.local_addr().expect("Error"));
+        let mut stub = GdbStub::new(session);
+        stub.serve(&listener).expect("Error");
+        report.finish(stub.into_session());
+        return;
+    }
+    if let Some(script_path) = options.script_path {
+        // Headless debugging from a file, or a REPL on stdin
+        let mut runner = ScriptRunner::new(session, Some(symbols), std::io::stdout());
+        // Screenshots keep the theme's colors, as in the terminal
+        runner.set_theme(theme.unwrap_or_default());
+        let failures = if script_path == "-" {
+            let stdin = std::io::stdin();
+            let prompt = stdin.is_terminal();
+            runner.run(stdin.lock(), prompt).expect("Error")
+        } else {
+            let script = std::fs::File::open(&script_path).expect("Error");
+            runner.run(BufReader::new(script), false).expect("Error")
+        };
+        report.finish(runner.into_session());
+        if failures > 0 {
+            eprintln!("{} command(s) failed", failures);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut display = backend_from_name(options.display.as_deref().unwrap_or("ansi")).expect("Error");
+    let mut debugger = Debugger::new(terminal_size().unwrap_or(DEFAULT_SIZE));
+    debugger.load_symbols(symbols);
+    if session.profiler().is_some() {
+        debugger.show_panel(Panel::Profile);
+    }
+    // Commands are read line by line on a separate thread so the emulator keeps running
+    let commands = options.debug.then(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    });
+    // Without the debugger nothing else reads stdin, so single keys are hotkeys
+    let raw_keys = (commands.is_none() && std::io::stdin().is_terminal()).then(RawKeys::enable).flatten();
+    let hotkeys = raw_keys.is_some().then(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for key in BufReader::new(std::io::stdin()).bytes().map_while(Result::ok) {
+                if tx.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    });
+    if commands.is_none() {
+        session.execute(&Command::Continue);
+    }
     // Clear screen from clutter
     print!("\x1B[2J");
     // Hide cursor
     print!("\x1B[?25l");
+    display.set_mode(LORES);
+    if let Some(theme) = &theme {
+        display.set_theme(theme);
+    }
+    // Screenshots keep the theme's colors, white on black without one
+    let palette = theme.unwrap_or_default();
+    display.draw(session.cpu().dump_vram());
+    let mut screen = Screen::new(debugger.layout().size);
+    let mut last_command = Command::Step(1);
+    let mut last_stop = None;
+    let mut message = String::new();
+    let mut pending = VecDeque::new();
+    let mut size_checked = Instant::now();
     loop {
-        let res = if let Some(res) = cpu.cycle() {
-            res
-        } else {
+        if size_checked.elapsed() >= RESIZE_POLL {
+            size_checked = Instant::now();
+            if debugger.resize(terminal_size().unwrap_or(debugger.layout().size)) {
+                redraw(display.as_mut(), &debugger, &mut screen, &session);
+            }
+        }
+        let keys: Vec<u8> = hotkeys.as_ref().map(|keys| keys.try_iter().collect()).unwrap_or_default();
+        if keys.contains(&QUIT_KEY) {
             break;
-        };
-        if let Some(vram) = res.0 {
-            Display::draw(&vram);
-        }
-        let registers = cpu.dump_registers();
-        let large_reg = cpu.dump_large_register();
-        let stack = cpu.dump_stack();
-        debugger.print_registers(&registers, large_reg);
-        debugger.print_stack(&stack);
-        debugger.print_clock(cpu.dump_clock());
-        debugger.print_codes(cpu.dump_program(),cpu.dump_pc());
-        std::thread::sleep(std::time::Duration::from_millis(SLEEP_TIME));
+        }
+        if keys.contains(&SCREENSHOT_KEY) {
+            message = take_screenshot(None, SCREENSHOT_SCALE, &session, &palette);
+            debugger.print_message(&mut screen, &message);
+        }
+        if let Some(commands) = &commands {
+            pending.extend(commands.try_iter());
+            let line = if session.is_running() {
+                // Only `pause`, keys and screenshots are handled mid-run, everything else waits for the next stop
+                let interrupt = pending.iter().position(|l| matches!(Command::parse(l, None), Ok(Command::Pause | Command::Keys(_) | Command::Screenshot(..))));
+                interrupt.and_then(|i| pending.remove(i))
+            } else {
+                debugger.print_panels(&mut screen, &session);
+                debugger.print_status(&mut screen, session.state(), last_stop.as_ref(), session.cpu().dump_pc());
+                debugger.print_prompt(&mut screen, &message);
+                print!("{}\x1B[?25h", screen.diff());
+                std::io::stdout().flush().expect("Error");
+                match pending.pop_front().map(Ok).unwrap_or_else(|| commands.recv_timeout(RESIZE_POLL)) {
+                    Ok(line) => Some(line),
+                    // Nothing typed yet, go round to check the terminal size
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            if let Some(line) = line {
+                print!("\x1B[?25l");
+                // The typed line was echoed on the prompt row
+                screen.invalidate_row(debugger.layout().status.1 + 2);
+                message.clear();
+                // An empty line repeats the previous command
+                let parsed = if line.trim().is_empty() { Ok(last_command.clone()) } else { Command::parse(&line, debugger.symbols()) };
+                match parsed {
+                    Ok(Command::Quit) => break,
+                    Ok(Command::Memory(view)) => debugger.apply_memory_command(view, session.cpu().dump_large_register()),
+                    Ok(Command::Sprites(view)) => {
+                        debugger.apply_sprite_command(view, session.last_draw());
+                        redraw(display.as_mut(), &debugger, &mut screen, &session);
+                    }
+                    Ok(Command::Screenshot(path, scale)) => message = take_screenshot(path, scale, &session, &palette),
+                    Ok(Command::Layout(layout)) => {
+                        debugger.apply_layout_command(layout);
+                        redraw(display.as_mut(), &debugger, &mut screen, &session);
+                    }
+                    Ok(command) => {
+                        if matches!(command, Command::Step(_) | Command::StepOver | Command::StepOut | Command::Continue | Command::RunTo(_)) {
+                            debugger.mark_memory(session.cpu().dump_memory());
+                        }
+                        if command == Command::StepOut && session.backtrace().is_empty() {
+                            message = "Not inside a subroutine".to_owned();
+                        }
+                        if let Some(stop) = session.execute(&command) {
+                            last_stop = Some(stop);
+                        }
+                        if let Command::Break(..) = command {
+                            if let Some(bp) = session.breakpoints().iter().last() {
+                                message = format!("Breakpoint {}", bp);
+                            }
+                        }
+                        if command == Command::Reset {
+                            last_stop = None;
+                        }
+                        // Anything that moves through history swaps the whole machine
+                        if matches!(command, Command::Reset | Command::StepBack(_) | Command::ReverseContinue | Command::ReverseUntil(_) | Command::Seek(_)) {
+                            display.draw(session.cpu().dump_vram());
+                        }
+                        last_command = command;
+                    }
+                    Err(err) => message = err,
+                }
+                continue;
+            }
+        }
+        let tick = session.tick();
+        if tick.frame_changed {
+            display.draw(session.cpu().dump_vram());
+        }
+        if let Some(stop) = tick.stop {
+            if commands.is_none() {
+                if let StopReason::Fault(fault) = &stop {
+                    debugger.print_fault(&mut screen, fault);
+                    screen.flush(&mut std::io::stdout()).expect("Error");
+                }
+                break;
+            }
+            last_stop = Some(stop);
+            continue;
+        }
+        debugger.print_panels(&mut screen, &session);
+        screen.flush(&mut std::io::stdout()).expect("Error");
+        std::thread::sleep(Duration::from_millis(SLEEP_TIME));
+    }
+    // Show cursor again
+    print!("\x1B[?25h");
+    drop(raw_keys);
+    if let Err(err) = display.finish() {
+        eprintln!("Error writing frames: {}", err);
+    }
+    report.finish(session);
+}
+
+///Saves the display as an image, by default named after the cycle, and says how it went
+fn take_screenshot(path: Option<String>, scale: usize, session: &Session, palette: &Theme) -> String {
+    let path = path.unwrap_or_else(|| screenshot_path(session.cycles()));
+    match save_screenshot(&path, LORES, session.cpu().dump_vram(), palette, scale) {
+        Ok(()) => format!("Saved {}", path),
+        Err(err) => format!("Cannot save {}: {}", path, err),
+    }
+}
+
+///Clears the terminal and draws everything again, after the panels moved
+fn redraw(display: &mut dyn DisplayBackend, debugger: &Debugger, screen: &mut Screen, session: &Session) {
+    print!("\x1B[2J");
+    screen.resize(debugger.layout().size);
+    display.redraw(session.cpu().dump_vram());
+    debugger.print_panels(screen, session);
+    screen.flush(&mut std::io::stdout()).expect("Error");
+}
+
+///Output written once the program exits
+struct Report {
+    profile_path: Option<String>,
+    coverage_path: Option<String>,
+    source_dir: PathBuf,    //Where the debug symbols' source files are
+    symbols: SymbolTable,
+}
+
+impl Report {
+    fn finish(&self, mut session: Session) {
+        if let Some(tracer) = session.set_tracer(None) {
+            if let Err(err) = tracer.finish() {
+                eprintln!("Error writing trace: {}", err);
+            }
+        }
+        if let (Some(path), Some(profiler)) = (&self.profile_path, session.profiler()) {
+            if let Err(err) = profiler.save(path, Some(&self.symbols)) {
+                eprintln!("Error writing profile: {}", err);
+            }
+        }
+        if let (Some(path), Some(coverage)) = (&self.coverage_path, session.coverage()) {
+            let program = session.cpu().dump_program();
+            eprintln!("Coverage: {}", coverage.summary(program, Some(&self.symbols)));
+            if let Err(err) = coverage.save(path, program, Some(&self.symbols), &self.source_dir) {
+                eprintln!("Error writing coverage: {}", err);
+            }
+        }
     }
 }
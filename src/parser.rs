@@ -1,5 +1,4 @@
-use core::panic;
-use std::{fs, vec};
+use std::fs;
 ///Type of operation that code represents
 #[derive( Debug, PartialEq, Eq, Copy, Clone )]
 pub enum OpCodeType{
@@ -17,6 +16,40 @@ pub enum OpCodeType{
     TIMER(u8),      //Delay program
     SOUND(u8),      //Play sound
     BCD(u8),        //Fancy BCD things
+    DATA(u8),       //Raw word that isn't an instruction
+}
+
+impl OpCodeType{
+    ///Name of the type without its payload, e.g. `FLOW`
+    pub fn name(&self)->&'static str{
+        match self{
+            OpCodeType::CALL(_)=>"CALL",
+            OpCodeType::DISPLAY(_)=>"DISPLAY",
+            OpCodeType::FLOW(_)=>"FLOW",
+            OpCodeType::COND(_)=>"COND",
+            OpCodeType::CONST(_)=>"CONST",
+            OpCodeType::ASSIG(_)=>"ASSIG",
+            OpCodeType::BITOP(_)=>"BITOP",
+            OpCodeType::MATH(_)=>"MATH",
+            OpCodeType::MEM(_)=>"MEM",
+            OpCodeType::RAND(_)=>"RAND",
+            OpCodeType::KEYOP(_)=>"KEYOP",
+            OpCodeType::TIMER(_)=>"TIMER",
+            OpCodeType::SOUND(_)=>"SOUND",
+            OpCodeType::BCD(_)=>"BCD",
+            OpCodeType::DATA(_)=>"DATA",
+        }
+    }
+
+    ///Finds a type by name ignoring case, the payload is taken from the first encoding of that type
+    pub fn from_name(name:&str)->Option<OpCodeType>{
+        ENCODINGS.iter().map(|e|e.oc_type).find(|t|t.name().eq_ignore_ascii_case(name))
+    }
+
+    ///Compares types ignoring the payload
+    pub fn same_kind(&self, other:&OpCodeType)->bool{
+        self.name()==other.name()
+    }
 }
 
 ///Specific op code identity
@@ -57,6 +90,7 @@ pub enum OpCodeIdentity{
     SetBcdR,            //BCD things
     DumpRegsToMemR,     //Dump V0-Reg to mem at addr const
     LoadRegsFromMemR,   //Load V0-reg from mem from addr const
+    Data,               //Raw word kept as is, e.g. sprites or padding in a ROM
 }
 ///Holds an op code and metadata for it
 #[derive( Debug, PartialEq, Eq, Copy, Clone )]
@@ -66,6 +100,7 @@ pub struct OpCode {
     pub op_code:u16
 }
 
+#[derive( Debug, PartialEq, Eq, Copy, Clone )]
 pub enum DataType {
     NNN {
         address: u16,
@@ -130,44 +165,193 @@ impl DataType {
 
 }
 
+///Layout of the operands packed into an op code
+#[derive( Debug, PartialEq, Eq, Copy, Clone )]
+pub enum Operands{
+    NNN,    //12 bit address
+    XNN,    //Register and 8 bit constant
+    XYN,    //Two registers and 4 bit constant
+    XY,     //Two registers
+    X,      //One register
+    None,   //No operands
+}
+
+///One row of the op code table shared by the decoder and encoder
+pub struct Encoding{
+    pub oc_id:OpCodeIdentity,
+    pub oc_type:OpCodeType,
+    pub mask:u16,           //Bits fixed by the instruction
+    pub pattern:u16,        //Value of the fixed bits
+    pub operands:Operands,
+    pub min_address:u16,    //Lowest NNN the instruction may take
+    pub syntax:&'static str,//Assembly form, operands in braces
+}
+
+const fn enc(oc_id:OpCodeIdentity, oc_type:OpCodeType, mask:u16, pattern:u16, operands:Operands, syntax:&'static str)->Encoding{
+    Encoding{oc_id, oc_type, mask, pattern, operands, min_address:0, syntax}
+}
+
+///Every op code, ordered so that the first matching row wins
+pub const ENCODINGS:[Encoding;35]=[
+    enc(OpCodeIdentity::ClrDisp,           OpCodeType::DISPLAY(1), 0xFFFF, 0x00E0, Operands::None, "CLS"),
+    enc(OpCodeIdentity::RetSub,            OpCodeType::FLOW(1),    0xFFFF, 0x00EE, Operands::None, "RET"),
+    Encoding{min_address:0x200, ..enc(OpCodeIdentity::CallMach, OpCodeType::CALL(1), 0xF000, 0x0000, Operands::NNN, "SYS {nnn}")},
+    enc(OpCodeIdentity::JumpAddr,          OpCodeType::FLOW(2),    0xF000, 0x1000, Operands::NNN, "JP {nnn}"),
+    enc(OpCodeIdentity::CallSub,           OpCodeType::FLOW(4),    0xF000, 0x2000, Operands::NNN, "CALL {nnn}"),
+    enc(OpCodeIdentity::SkipEqRC,          OpCodeType::COND(1),    0xF000, 0x3000, Operands::XNN, "SE {x}, {nn}"),
+    enc(OpCodeIdentity::SkipNqRC,          OpCodeType::COND(2),    0xF000, 0x4000, Operands::XNN, "SNE {x}, {nn}"),
+    enc(OpCodeIdentity::SkipEqRR,          OpCodeType::COND(4),    0xF00F, 0x5000, Operands::XY, "SE {x}, {y}"),
+    enc(OpCodeIdentity::SetRC,             OpCodeType::CONST(1),   0xF000, 0x6000, Operands::XNN, "LD {x}, {nn}"),
+    enc(OpCodeIdentity::AddNcRC,           OpCodeType::CONST(2),   0xF000, 0x7000, Operands::XNN, "ADD {x}, {nn}"),
+    enc(OpCodeIdentity::SetRR,             OpCodeType::ASSIG(1),   0xF00F, 0x8000, Operands::XY, "LD {x}, {y}"),
+    enc(OpCodeIdentity::OrRR,              OpCodeType::BITOP(1),   0xF00F, 0x8001, Operands::XY, "OR {x}, {y}"),
+    enc(OpCodeIdentity::AndRR,             OpCodeType::BITOP(2),   0xF00F, 0x8002, Operands::XY, "AND {x}, {y}"),
+    enc(OpCodeIdentity::XorRR,             OpCodeType::BITOP(4),   0xF00F, 0x8003, Operands::XY, "XOR {x}, {y}"),
+    enc(OpCodeIdentity::AddRR,             OpCodeType::MATH(1),    0xF00F, 0x8004, Operands::XY, "ADD {x}, {y}"),
+    enc(OpCodeIdentity::SubRRR,            OpCodeType::MATH(2),    0xF00F, 0x8005, Operands::XY, "SUB {x}, {y}"),
+    enc(OpCodeIdentity::RshiftR,           OpCodeType::BITOP(8),   0xF00F, 0x8006, Operands::X, "SHR {x}"),
+    enc(OpCodeIdentity::SubLRR,            OpCodeType::MATH(4),    0xF00F, 0x8007, Operands::XY, "SUBN {x}, {y}"),
+    enc(OpCodeIdentity::LshiftR,           OpCodeType::BITOP(16),  0xF00F, 0x800E, Operands::X, "SHL {x}"),
+    enc(OpCodeIdentity::SkipNqRR,          OpCodeType::COND(8),    0xF00F, 0x9000, Operands::XY, "SNE {x}, {y}"),
+    enc(OpCodeIdentity::SetAddrRegC,       OpCodeType::MEM(1),     0xF000, 0xA000, Operands::NNN, "LD I, {nnn}"),
+    enc(OpCodeIdentity::JumpAddrCR,        OpCodeType::FLOW(8),    0xF000, 0xB000, Operands::NNN, "JP V0, {nnn}"),
+    enc(OpCodeIdentity::RandRC,            OpCodeType::RAND(1),    0xF000, 0xC000, Operands::XNN, "RND {x}, {nn}"),
+    enc(OpCodeIdentity::DrawDispRRC,       OpCodeType::DISPLAY(2), 0xF000, 0xD000, Operands::XYN, "DRW {x}, {y}, {n}"),
+    enc(OpCodeIdentity::SkipKeyPressedR,   OpCodeType::KEYOP(1),   0xF0FF, 0xE09E, Operands::X, "SKP {x}"),
+    enc(OpCodeIdentity::SkipNKeyPressedR,  OpCodeType::KEYOP(2),   0xF0FF, 0xE0A1, Operands::X, "SKNP {x}"),
+    enc(OpCodeIdentity::GetDelayR,         OpCodeType::TIMER(1),   0xF0FF, 0xF007, Operands::X, "LD {x}, DT"),
+    enc(OpCodeIdentity::AwaitGetKeyDownR,  OpCodeType::KEYOP(4),   0xF0FF, 0xF00A, Operands::X, "LD {x}, K"),
+    enc(OpCodeIdentity::SetDelayR,         OpCodeType::TIMER(2),   0xF0FF, 0xF015, Operands::X, "LD DT, {x}"),
+    enc(OpCodeIdentity::SetSoundR,         OpCodeType::SOUND(1),   0xF0FF, 0xF018, Operands::X, "LD ST, {x}"),
+    enc(OpCodeIdentity::AddAddrRegR,       OpCodeType::MEM(2),     0xF0FF, 0xF01E, Operands::X, "ADD I, {x}"),
+    enc(OpCodeIdentity::SetAddrRegSpriteR, OpCodeType::MEM(4),     0xF0FF, 0xF029, Operands::X, "LD F, {x}"),
+    enc(OpCodeIdentity::SetBcdR,           OpCodeType::MEM(8),     0xF0FF, 0xF033, Operands::X, "LD B, {x}"),
+    enc(OpCodeIdentity::DumpRegsToMemR,    OpCodeType::MEM(16),    0xF0FF, 0xF055, Operands::X, "LD [I], {x}"),
+    enc(OpCodeIdentity::LoadRegsFromMemR,  OpCodeType::MEM(32),    0xF0FF, 0xF065, Operands::X, "LD {x}, [I]"),
+];
+
+///Row for words that decode to no instruction, kept apart so `decode` never matches it
+pub const DATA_WORD:Encoding=enc(OpCodeIdentity::Data, OpCodeType::DATA(1), 0x0000, 0x0000, Operands::None, "DW {word}");
+
+///Table row describing a raw op code, `None` if it isn't a valid instruction
+pub fn decode(op_code:u16)->Option<&'static Encoding>{
+    ENCODINGS.iter().find(|e| op_code&e.mask==e.pattern && op_code&0x0FFF>=e.min_address)
+}
+
+///Table row for an op code identity
+pub fn encoding(oc_id:OpCodeIdentity)->&'static Encoding{
+    if oc_id==OpCodeIdentity::Data{
+        return &DATA_WORD;
+    }
+    ENCODINGS.iter().find(|e| e.oc_id==oc_id).expect("Every identity has an encoding")
+}
+
+#[derive( Debug )]
+pub enum ParseError{
+    Io(std::io::Error),
+    OddLength(usize),                       //A ROM is made of whole words
+    BadToken{line:usize, token:String},     //Not a 4 digit hex word
+    UnknownOpCode{line:usize, op_code:u16}, //Listings hold instructions only
+}
+
+impl std::fmt::Display for ParseError{
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ParseError::Io(err) => write!(f, "{}", err),
+            ParseError::OddLength(len) => write!(f, "invalid ROM, odd number of bytes ({})", len),
+            ParseError::BadToken{line, token} => write!(f, "line {}: invalid op code {}, hexadecimal token of length 4", line, token),
+            ParseError::UnknownOpCode{line, op_code} => write!(f, "line {}: unknown op code {:04X}", line, op_code),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError{
+    fn from(err:std::io::Error)->Self{
+        ParseError::Io(err)
+    }
+}
+
+#[derive( Debug, PartialEq, Eq, Copy, Clone )]
+pub enum EncodeError{
+    WrongOperands{expected:Operands},                   //DataType doesn't match the instruction
+    OutOfRange{field:&'static str, value:u16, min:u16, max:u16},
+}
+
+impl std::fmt::Display for EncodeError{
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            EncodeError::WrongOperands{expected} => write!(f, "expected {:?} operands", expected),
+            EncodeError::OutOfRange{field, value, min, max} => write!(f, "{} = {:#X} is outside {:#X}..={:#X}", field, value, min, max),
+        }
+    }
+}
+
+fn check_range(field:&'static str, value:u16, min:u16, max:u16)->Result<u16, EncodeError>{
+    if value<min||value>max{
+        return Err(EncodeError::OutOfRange{field, value, min, max});
+    }
+    Ok(value)
+}
+
+///Packs an identity and its operands into an op code, the inverse of `OpCode::get_data`
+pub fn encode(oc_id:OpCodeIdentity, data:DataType)->Result<u16, EncodeError>{
+    let e=encoding(oc_id);
+    let operands=match (e.operands, data){
+        (Operands::NNN, DataType::NNN{address}) => check_range("address", address, e.min_address, 0xFFF)?,
+        (Operands::XNN, DataType::XNN{x, constant}) => check_range("x", x as u16, 0, 0xF)?<<8|constant as u16,
+        (Operands::XYN, DataType::XYN{x, y, constant}) => {
+            check_range("x", x as u16, 0, 0xF)?<<8|check_range("y", y as u16, 0, 0xF)?<<4|check_range("n", constant as u16, 0, 0xF)?
+        }
+        (Operands::XY, DataType::XY{x, y}) => check_range("x", x as u16, 0, 0xF)?<<8|check_range("y", y as u16, 0, 0xF)?<<4,
+        (Operands::X, DataType::X{x}) => check_range("x", x as u16, 0, 0xF)?<<8,
+        (Operands::None, DataType::None) => 0,
+        (expected, _) => return Err(EncodeError::WrongOperands{expected}),
+    };
+    Ok(e.pattern|operands)
+}
+
 impl OpCode {
+    ///Decodes a raw op code, `None` if it isn't a valid instruction
+    pub fn decode(op_code:u16)->Option<OpCode>{
+        decode(op_code).map(|e| OpCode{oc_type:e.oc_type, oc_id:e.oc_id, op_code})
+    }
+
+    ///Decodes a word of a ROM, one that isn't an instruction becomes a data word
+    pub fn from_word(op_code:u16)->OpCode{
+        OpCode::decode(op_code).unwrap_or(OpCode{oc_type:DATA_WORD.oc_type, oc_id:OpCodeIdentity::Data, op_code})
+    }
+
+    pub fn encode(oc_id:OpCodeIdentity, data:DataType)->Result<OpCode, EncodeError>{
+        let op_code=encode(oc_id, data)?;
+        Ok(OpCode{oc_type:encoding(oc_id).oc_type, oc_id, op_code})
+    }
+
+    ///Assembly text for the op code, e.g. `DRW V3, V4, 8`
+    pub fn mnemonic(&self)->String{
+        let reg=|r:u8| format!("V{:X}", r);
+        let fields:Vec<(&str, String)>=match self.get_data(){
+            DataType::NNN{address} => vec![("{nnn}", format!("{:#05X}", address))],
+            DataType::XNN{x, constant} => vec![("{x}", reg(x)), ("{nn}", format!("{:#04X}", constant))],
+            DataType::XYN{x, y, constant} => vec![("{x}", reg(x)), ("{y}", reg(y)), ("{n}", constant.to_string())],
+            DataType::XY{x, y} => vec![("{x}", reg(x)), ("{y}", reg(y))],
+            DataType::X{x} => vec![("{x}", reg(x))],
+            DataType::None => Vec::new(),
+        };
+        let mut text=encoding(self.oc_id).syntax.replace("{word}", &format!("{:#06X}", self.op_code));
+        for (field, value) in fields{
+            text=text.replace(field, &value);
+        }
+        text
+    }
+
     pub fn get_data(&self) -> DataType {
-        let f = match self.oc_id {
-            OpCodeIdentity::CallMach => DataType::nnn,
-            OpCodeIdentity::ClrDisp => DataType::none,
-            OpCodeIdentity::RetSub => DataType::none,
-            OpCodeIdentity::JumpAddr => DataType::nnn,
-            OpCodeIdentity::CallSub => DataType::nnn,
-            OpCodeIdentity::SkipEqRC => DataType::xnn,
-            OpCodeIdentity::SkipNqRC => DataType::xnn,
-            OpCodeIdentity::SkipEqRR => DataType::xy,
-            OpCodeIdentity::SetRC => DataType::xnn,
-            OpCodeIdentity::AddNcRC => DataType::xnn,
-            OpCodeIdentity::SetRR => DataType::xy,
-            OpCodeIdentity::OrRR => DataType::xy,
-            OpCodeIdentity::AndRR => DataType::xy,
-            OpCodeIdentity::XorRR => DataType::xy,
-            OpCodeIdentity::AddRR => DataType::xy,
-            OpCodeIdentity::SubRRR => DataType::xy,
-            OpCodeIdentity::RshiftR => DataType::xy,
-            OpCodeIdentity::SubLRR => DataType::xy,
-            OpCodeIdentity::LshiftR => DataType::xy,
-            OpCodeIdentity::SkipNqRR => DataType::xy,
-            OpCodeIdentity::SetAddrRegC => DataType::nnn,
-            OpCodeIdentity::JumpAddrCR => DataType::nnn,
-            OpCodeIdentity::RandRC => DataType::xnn,
-            OpCodeIdentity::DrawDispRRC => DataType::xyn,
-            OpCodeIdentity::SkipKeyPressedR => DataType::x,
-            OpCodeIdentity::SkipNKeyPressedR => DataType::x,
-            OpCodeIdentity::GetDelayR => DataType::x,
-            OpCodeIdentity::AwaitGetKeyDownR => DataType::x,
-            OpCodeIdentity::SetDelayR => DataType::x,
-            OpCodeIdentity::SetSoundR => DataType::x,
-            OpCodeIdentity::AddAddrRegR => DataType::x,
-            OpCodeIdentity::SetAddrRegSpriteR => DataType::x,
-            OpCodeIdentity::SetBcdR => DataType::x,
-            OpCodeIdentity::DumpRegsToMemR => DataType::x,
-            OpCodeIdentity::LoadRegsFromMemR => DataType::x,
+        let f = match encoding(self.oc_id).operands {
+            Operands::NNN => DataType::nnn,
+            Operands::XNN => DataType::xnn,
+            Operands::XYN => DataType::xyn,
+            Operands::XY => DataType::xy,
+            Operands::X => DataType::x,
+            Operands::None => DataType::none,
         };
         f(self.op_code)
     }
@@ -199,249 +383,11 @@ fn check_op_code(op_code:&String)->bool{
             _         => return false,
        };
    }
-   return true;
-}
-fn get_oc_id(op_code:u16)->OpCodeIdentity{
-    let w1:u16=(op_code&0xF000)>>12;
-    let w2:u16=(op_code&0x0F00)>>8;
-    let w3:u16=(op_code&0x00F0)>>4;
-    let w4:u16=op_code&0x000F;
-    if w1==0x0{
-        if w2>=0x2{
-            return OpCodeIdentity::CallMach;
-        }
-        if op_code==0x00E0{
-            return OpCodeIdentity::ClrDisp;
-        }
-        if op_code==0x00EE{
-            return OpCodeIdentity::RetSub;
-        }
-        panic!("");
-    }
-    if w1==0x1{return OpCodeIdentity::JumpAddr;}
-    if w1==0x2{return OpCodeIdentity::CallSub;}
-    if w1==0x3{return OpCodeIdentity::SkipEqRC;}
-    if w1==0x4{return OpCodeIdentity::SkipNqRC;}
-    if w1==0x5{
-        if w4==0x0{
-            return OpCodeIdentity::SkipEqRR;
-        }
-        panic!("");
-    }
-    if w1==0x6{return OpCodeIdentity::SetRC;}
-    if w1==0x7{return OpCodeIdentity::AddNcRC;}
-    if w1==0x8{
-        if w4==0x0{
-            return OpCodeIdentity::SetRR;
-        }
-        if w4==0x1{
-            return OpCodeIdentity::OrRR;
-        }
-        if w4==0x2{
-            return OpCodeIdentity::AndRR;
-        }
-        if w4==0x3{
-            return OpCodeIdentity::XorRR;
-        }
-        if w4==0x4{
-            return OpCodeIdentity::AddRR;
-        }
-        if w4==0x5{
-            return OpCodeIdentity::SubRRR;
-        }
-        if w4==0x6{
-            return OpCodeIdentity::RshiftR;
-        }
-        if w4==0x7{
-            return OpCodeIdentity::SubLRR;
-        }
-        if w4==0xE{
-            return OpCodeIdentity::LshiftR;
-        }
-        panic!("");
-    }
-    if w1==0x9{
-        if w4==0x0{
-            return OpCodeIdentity::SkipNqRR;
-        }
-        panic!("");
-    }
-    if w1==0xA{
-        return OpCodeIdentity::SetAddrRegC;
-    }
-    if w1==0xB{
-        return OpCodeIdentity::JumpAddrCR;
-    }
-    if w1==0xC{
-        return OpCodeIdentity::RandRC;
-    }
-    if w1==0xD{
-        return OpCodeIdentity::DrawDispRRC;
-    }
-    if w1==0xE{
-        if w4==0xE&&w3==0x9{
-            return OpCodeIdentity::SkipKeyPressedR;
-        }
-        if w4==0x1&&w3==0xA{
-            return OpCodeIdentity::SkipNKeyPressedR;
-        }
-        panic!("")
-    }
-    if w1==0xF{
-        let w34=(w3<<4)|w4;
-        if w34==0x07{
-            return OpCodeIdentity::GetDelayR;
-        }
-        if w34==0x0A{
-            return OpCodeIdentity::AwaitGetKeyDownR;
-        }
-        if w34==0x15{
-            return OpCodeIdentity::SetDelayR;
-        }
-        if w34==0x18{
-            return OpCodeIdentity::SetSoundR;
-        }
-        if w34==0x1E{
-            return OpCodeIdentity::AddAddrRegR;
-        }
-        if w34==0x29{
-            return OpCodeIdentity::SetAddrRegSpriteR;
-        }
-        if w34==0x33{
-            return OpCodeIdentity::SetBcdR;
-        }
-        if w34==0x55{
-            return OpCodeIdentity::DumpRegsToMemR;
-        }
-        if w34==0x65{
-            return OpCodeIdentity::LoadRegsFromMemR;
-        }
-        panic!("");
-    }
-    panic!("");
+   true
 }
-fn get_oc_type(op_code:u16)->OpCodeType{
-    let w1:u16=(op_code&0xF000)>>12;
-    let w2:u16=(op_code&0x0F00)>>8;
-    let w3:u16=(op_code&0x00F0)>>4;
-    let w4:u16=op_code&0x000F;
-    if w1==0x0{
-        if w2>=0x2{
-            return OpCodeType::CALL(1);
-        }
-        if op_code==0x00E0{
-            return OpCodeType::DISPLAY(1);
-        }
-        if op_code==0x00EE{
-            return OpCodeType::FLOW(1);
-        }
-        panic!("");
-    }
-    if w1==0x1{return OpCodeType::FLOW(2);}
-    if w1==0x2{return OpCodeType::FLOW(4);}
-    if w1==0x3{return OpCodeType::COND(1);}
-    if w1==0x4{return OpCodeType::COND(2);}
-    if w1==0x5{
-        if w4==0x0{
-            return OpCodeType::COND(4);
-        }
-        panic!("");
-    }
-    if w1==0x6{return OpCodeType::CONST(1);}
-    if w1==0x7{return OpCodeType::CONST(2);}
-    if w1==0x8{
-        if w4==0x0{
-            return OpCodeType::ASSIG(1);
-        }
-        if w4==0x1{
-            return OpCodeType::BITOP(1);
-        }
-        if w4==0x2{
-            return OpCodeType::BITOP(2);
-        }
-        if w4==0x3{
-            return OpCodeType::BITOP(4);
-        }
-        if w4==0x4{
-            return OpCodeType::MATH(1);
-        }
-        if w4==0x5{
-            return OpCodeType::MATH(2);
-        }
-        if w4==0x6{
-            return OpCodeType::BITOP(8);
-        }
-        if w4==0x7{
-            return OpCodeType::MATH(4);
-        }
-        if w4==0xE{
-            return OpCodeType::BITOP(16);
-        }
-        panic!("");
-    }
-    if w1==0x9{
-        if w4==0x0{
-            return OpCodeType::COND(8);
-        }
-        panic!("");
-    }
-    if w1==0xA{
-        return OpCodeType::MEM(1);
-    }
-    if w1==0xB{
-        return OpCodeType::FLOW(8);
-    }
-    if w1==0xC{
-        return OpCodeType::RAND(1);
-    }
-    if w1==0xD{
-        return OpCodeType::DISPLAY(2);
-    }
-    if w1==0xE{
-        if w4==0xE&&w3==0x9{
-            return OpCodeType::KEYOP(1);
-        }
-        if w4==0x1&&w3==0xA{
-            return OpCodeType::KEYOP(2);
-        }
-        panic!("")
-    }
-    if w1==0xF{
-        let w34=(w3<<4)|w4;
-        if w34==0x07{
-            return OpCodeType::TIMER(1);
-        }
-        if w34==0x0A{
-            return OpCodeType::KEYOP(4);
-        }
-        if w34==0x15{
-            return OpCodeType::TIMER(2);
-        }
-        if w34==0x18{
-            return OpCodeType::SOUND(1);
-        }
-        if w34==0x1E{
-            return OpCodeType::MEM(2);
-        }
-        if w34==0x29{
-            return OpCodeType::MEM(4);
-        }
-        if w34==0x33{
-            return OpCodeType::MEM(8);
-        }
-        if w34==0x55{
-            return OpCodeType::MEM(16);
-        }
-        if w34==0x65{
-            return OpCodeType::MEM(32);
-        }
-        panic!("");
-    }
-    panic!("");
-}
-fn parse_oc(op_code:String)->OpCode{
+fn parse_oc(line:usize, op_code:String)->Result<OpCode, ParseError>{
     if !check_op_code(&op_code){
-        panic!("Invalid op code {}, hexadecimal token of length 4\n", op_code);
+        return Err(ParseError::BadToken{line, token:op_code});
     }
     let mut oc_val:u16=0b0;
     for (i,symbol) in (*op_code).chars().enumerate() {
@@ -466,22 +412,28 @@ fn parse_oc(op_code:String)->OpCode{
         };
         oc_val|=symb_val<<(4*(3-i));
     }
-    return OpCode { oc_type: get_oc_type(oc_val), oc_id:get_oc_id(oc_val), op_code: oc_val };
+    OpCode::decode(oc_val).ok_or(ParseError::UnknownOpCode{line, op_code:oc_val})
 }
-pub fn parse_file(fp: &str)->Vec<OpCode>{
-    let contents=fs::read_to_string(fp).expect("Error");
-    let code_lines=contents.lines();
-    let mut lns:Vec<OpCode>=Vec::new();
-    for line in code_lines{
-        lns.push(parse_oc(line.to_string()));
+///Loads a program, a raw ROM for `.ch8` files and a hex listing otherwise
+pub fn parse_file(fp: &str)->Result<Vec<OpCode>, ParseError>{
+    if fp.ends_with(".ch8"){
+        return parse_binary(&fs::read(fp)?);
     }
-    return lns;
+    try_parse_text(&fs::read_to_string(fp)?)
 }
+///Parses a hex listing, one op code per line
+pub fn try_parse_text(text:&str)->Result<Vec<OpCode>, ParseError>{
+    text.lines().enumerate().map(|(i, line)| parse_oc(i+1, line.to_string())).collect()
+}
+///Like `try_parse_text` but panics on a bad listing, for programs written in the source
 pub fn parse_text(text:String)->Vec<OpCode>{
-    let code_lines=text.lines();
-    let mut lns:Vec<OpCode>=Vec::new();
-    for line in code_lines{
-        lns.push(parse_oc(line.to_string()));
+    try_parse_text(&text).unwrap_or_else(|e| panic!("{}", e))
+}
+///Parses a raw ROM of big-endian op codes. Words that aren't instructions are kept as data
+///words, so writing the program back gives the same bytes
+pub fn parse_binary(bytes:&[u8])->Result<Vec<OpCode>, ParseError>{
+    if !bytes.len().is_multiple_of(2){
+        return Err(ParseError::OddLength(bytes.len()));
     }
-    return lns;
+    Ok(bytes.chunks(2).map(|word| OpCode::from_word(u16::from_be_bytes([word[0], word[1]]))).collect())
 }
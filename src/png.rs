@@ -0,0 +1,80 @@
+use crate::theme::Rgb;
+
+///Bytes every PNG file starts with
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+///Largest stored deflate block
+const MAX_STORED: usize = 0xFFFF;
+
+///CRC-32 as PNG chunks and zip files use it, reflected with polynomial 0xEDB88320
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+///Adler-32 checksum ending a zlib stream
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % MOD;
+        b = (b + a) % MOD;
+    }
+    (b << 16) | a
+}
+
+///zlib stream of uncompressed deflate blocks, which any inflater reads
+pub fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // 32K window, no dictionary, fastest level; the header must be a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+///8-bit indexed color PNG, `pixels` holding one palette index per pixel row by row
+pub fn encode_indexed(width: usize, height: usize, palette: &[Rgb], pixels: &[u8]) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    let mut header = Vec::new();
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // Bit depth 8, palette color, deflate, adaptive filtering, no interlace
+    header.extend([8, 3, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &header);
+    let colors: Vec<u8> = palette.iter().flat_map(|c| [c.0, c.1, c.2]).collect();
+    chunk(&mut out, b"PLTE", &colors);
+    // Every row starts with filter type 0, none
+    let mut rows = Vec::with_capacity((width + 1) * height);
+    for row in pixels[..width * height].chunks(width) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    chunk(&mut out, b"IDAT", &zlib_stored(&rows));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    cpu::Chip8,
+    parser::{OpCode, OpCodeIdentity},
+    symbols::SymbolTable,
+};
+
+///Rows in each section of the report
+const REPORT_ROWS: usize = 20;
+
+///Cost of one subroutine, in executed instructions
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct SubroutineCost {
+    pub calls: u64,
+    pub inclusive: u64, //Including the subroutines it called
+    pub exclusive: u64, //Only its own instructions
+}
+
+///Subroutine call that hasn't returned yet
+#[derive(Debug, Clone)]
+struct OpenCall {
+    target: u16,
+    start: u64,     //Instructions executed when it was entered
+    children: u64,  //Inclusive cost of the calls it made
+}
+
+///Counts executed instructions by address, subroutine and op code type.
+///
+///Cost is measured in instructions since every one takes a cycle. A call runs from the
+///instruction after its `CALL` up to and including the `RET`. Instructions re-executed after a
+///rewind are counted again, the profile covers everything the machine ran.
+#[derive(Debug, Default, Clone)]
+pub struct Profiler {
+    executed: u64,
+    counts: Vec<u64>,   //Indexed by address
+    mix: BTreeMap<&'static str, u64>,
+    subroutines: BTreeMap<u16, SubroutineCost>,
+    calls: Vec<OpenCall>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    ///Records the instruction that just ran at `pc`
+    pub fn record(&mut self, pc: u16, oc: &OpCode, cpu: &Chip8) {
+        self.executed += 1;
+        let index = pc as usize;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        *self.mix.entry(oc.oc_type.name()).or_default() += 1;
+        match oc.oc_id {
+            OpCodeIdentity::CallSub => {
+                self.calls.push(OpenCall { target: cpu.dump_pc(), start: self.executed, children: 0 });
+            }
+            // A return without a recorded call came from before profiling started
+            OpCodeIdentity::RetSub => self.ret(),
+            _ => (),
+        }
+    }
+
+    ///Forgets the open calls, for when the machine is replaced and they will never return
+    pub fn unwind(&mut self) {
+        self.calls.clear();
+    }
+
+    ///Closes the innermost open call, charging its cost to the caller
+    fn ret(&mut self) {
+        let Some(call) = self.calls.pop() else { return };
+        let inclusive = self.executed - call.start;
+        let cost = self.subroutines.entry(call.target).or_default();
+        cost.calls += 1;
+        cost.exclusive += inclusive - call.children;
+        // Recursive calls are already inside the outer call's inclusive cost
+        if !self.calls.iter().any(|c| c.target == call.target) {
+            cost.inclusive += inclusive;
+        }
+        if let Some(caller) = self.calls.last_mut() {
+            caller.children += inclusive;
+        }
+    }
+
+    ///Addresses by execution count, most executed first
+    pub fn hot_spots(&self) -> Vec<(u16, u64)> {
+        let mut spots: Vec<(u16, u64)> =
+            self.counts.iter().enumerate().filter(|(_, n)| **n > 0).map(|(addr, n)| (addr as u16, *n)).collect();
+        spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    ///Subroutines by inclusive cost, calls still open are counted as if they returned now
+    pub fn subroutines(&self) -> Vec<(u16, SubroutineCost)> {
+        let mut finished = self.clone();
+        while !finished.calls.is_empty() {
+            finished.ret();
+        }
+        let mut costs: Vec<(u16, SubroutineCost)> = finished.subroutines.into_iter().collect();
+        costs.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        costs
+    }
+
+    ///Executed instructions per op code type, most common first
+    pub fn mix(&self) -> Vec<(&'static str, u64)> {
+        let mut mix: Vec<(&'static str, u64)> = self.mix.iter().map(|(name, n)| (*name, *n)).collect();
+        mix.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+        mix
+    }
+
+    ///Writes the ranked hot spots, subroutines and instruction mix as text
+    pub fn write_report(&self, out: &mut impl Write, symbols: Option<&SymbolTable>) -> io::Result<()> {
+        let describe = |addr: u16| match symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{:#05X}", addr),
+        };
+        let share = |n: u64| n as f64 * 100.0 / self.executed.max(1) as f64;
+        writeln!(out, "Profile of {} instructions", self.executed)?;
+        writeln!(out, "\nHot spots:\n{:>10} {:>6}  address", "count", "%")?;
+        for (addr, count) in self.hot_spots().into_iter().take(REPORT_ROWS) {
+            writeln!(out, "{:>10} {:>6.2}  {}", count, share(count), describe(addr))?;
+        }
+        writeln!(out, "\nSubroutines:\n{:>8} {:>10} {:>10} {:>6}  subroutine", "calls", "inclusive", "exclusive", "%")?;
+        for (addr, cost) in self.subroutines().into_iter().take(REPORT_ROWS) {
+            writeln!(out, "{:>8} {:>10} {:>10} {:>6.2}  {}", cost.calls, cost.inclusive, cost.exclusive, share(cost.inclusive), describe(addr))?;
+        }
+        writeln!(out, "\nInstruction mix:\n{:>10} {:>6}  type", "count", "%")?;
+        for (name, count) in self.mix() {
+            writeln!(out, "{:>10} {:>6.2}  {}", count, share(count), name)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, symbols: Option<&SymbolTable>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_report(&mut out, symbols)?;
+        out.flush()
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fmt::{self, Write as _},
+    io,
+};
+
+///A character and the index of the SGR style it is drawn in, 0 being the default
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+struct Cell {
+    ch: char,
+    style: usize,
+}
+
+const BLANK: Cell = Cell { ch: ' ', style: 0 };
+///Longest run of unchanged cells written again rather than moved over
+const SHORT_GAP: usize = 4;
+///Never drawn, so a cell holding it always differs from what is written over it
+const UNKNOWN: Cell = Cell { ch: '\0', style: usize::MAX };
+
+///Off-screen copy of the terminal. Text written to it lands in cells, following the cursor
+///move, clear line, clear screen and style escapes, and `diff` returns only what changed
+///since the terminal was last brought up to date
+pub struct Screen {
+    size: (usize, usize),
+    cells: Vec<Cell>,
+    shown: Vec<Cell>,                   //What the terminal shows, as of the last diff
+    styles: Vec<String>,                //SGR escapes by index, 0 is the default style
+    style: usize,
+    cursor: (usize, usize),             //Zero based column and row
+    terminal_cursor: Option<(usize, usize)>,
+    escape: Option<String>,             //Escape split across writes, after the `ESC`
+}
+
+impl Screen {
+    ///Blank screen of `size` columns and rows, the terminal is assumed blank too
+    pub fn new(size: (usize, usize)) -> Self {
+        Self {
+            size,
+            cells: vec![BLANK; size.0 * size.1],
+            shown: vec![BLANK; size.0 * size.1],
+            styles: vec![String::new()],
+            style: 0,
+            cursor: (0, 0),
+            terminal_cursor: None,
+            escape: None,
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        self.size
+    }
+
+    ///Starts over at a new size, for after the terminal was cleared
+    pub fn resize(&mut self, size: (usize, usize)) {
+        *self = Screen::new(size);
+    }
+
+    ///Writes formatted text, escapes included
+    pub fn print(&mut self, args: fmt::Arguments) {
+        // Writing to cells can't fail
+        let _ = self.write_fmt(args);
+    }
+
+    ///Marks a row, counted from 1 like the cursor escapes, as changed behind the screen's back,
+    ///e.g. by echoed input, so the next diff writes it in full
+    pub fn invalidate_row(&mut self, row: usize) {
+        if (1..=self.size.1).contains(&row) {
+            let start = (row - 1) * self.size.0;
+            self.shown[start..start + self.size.0].fill(UNKNOWN);
+        }
+        self.forget_cursor();
+    }
+
+    ///The terminal cursor was moved behind the screen's back
+    pub fn forget_cursor(&mut self) {
+        self.terminal_cursor = None;
+    }
+
+    ///Text of a row counted from 1, without styles
+    pub fn row(&self, row: usize) -> String {
+        let start = (row - 1) * self.size.0;
+        self.cells[start..start + self.size.0].iter().map(|c| c.ch).collect()
+    }
+
+    ///Cursor moves, styles and text bringing the terminal up to date, then a move to the cursor.
+    ///Empty when nothing changed
+    pub fn diff(&mut self) -> String {
+        let mut out = String::new();
+        let mut style = None;
+        let mut at = self.terminal_cursor;
+        let width = self.size.0;
+        for i in 0..self.cells.len() {
+            if self.cells[i] == self.shown[i] {
+                continue;
+            }
+            let pos = (i % width, i / width);
+            match at {
+                Some(cursor) if cursor == pos => {}
+                // A few unchanged cells are cheaper to write again than a cursor move
+                Some((x, y)) if y == pos.1 && x < pos.0 && pos.0 - x <= SHORT_GAP => {
+                    for cell in &self.cells[x + y * width..i] {
+                        self.push_cell(&mut out, &mut style, *cell);
+                    }
+                }
+                _ => {
+                    let _ = write!(out, "\x1B[{};{}H", pos.1 + 1, pos.0 + 1);
+                }
+            }
+            self.push_cell(&mut out, &mut style, self.cells[i]);
+            self.shown[i] = self.cells[i];
+            at = Some((pos.0 + 1, pos.1));
+        }
+        if style.is_some_and(|s| s != 0) {
+            out.push_str("\x1B[0m");
+        }
+        // With nothing written and the cursor unknown there is nothing to put right
+        if at.is_some_and(|at| at != self.cursor) {
+            let _ = write!(out, "\x1B[{};{}H", self.cursor.1 + 1, self.cursor.0 + 1);
+        }
+        if at.is_some() {
+            self.terminal_cursor = Some(self.cursor);
+        }
+        out
+    }
+
+    ///Writes a cell, switching style first if it differs from the one in effect
+    fn push_cell(&self, out: &mut String, style: &mut Option<usize>, cell: Cell) {
+        if *style != Some(cell.style) {
+            out.push_str("\x1B[0m");
+            out.push_str(&self.styles[cell.style]);
+            *style = Some(cell.style);
+        }
+        out.push(cell.ch);
+    }
+
+    ///Sends the diff in one write, nothing at all when nothing changed
+    pub fn flush(&mut self, out: &mut impl io::Write) -> io::Result<()> {
+        let diff = self.diff();
+        if diff.is_empty() {
+            return Ok(());
+        }
+        out.write_all(diff.as_bytes())?;
+        out.flush()
+    }
+
+    fn put(&mut self, ch: char) {
+        let (x, y) = self.cursor;
+        match ch {
+            '\n' => self.cursor = (0, y + 1),
+            '\r' => self.cursor.0 = 0,
+            _ => {
+                if x < self.size.0 && y < self.size.1 {
+                    self.cells[x + y * self.size.0] = Cell { ch, style: self.style };
+                }
+                self.cursor.0 += 1;
+            }
+        }
+    }
+
+    ///Applies a CSI escape, `params` between the `[` and the final byte
+    fn control(&mut self, params: &str, command: char) {
+        let number = |i: usize| params.split(';').nth(i).and_then(|n| n.parse::<usize>().ok()).unwrap_or(1);
+        let width = self.size.0;
+        match command {
+            'H' => self.cursor = (number(1).saturating_sub(1), number(0).saturating_sub(1)),
+            'K' if self.cursor.1 < self.size.1 => {
+                let row = self.cursor.1 * width;
+                let blank = Cell { ch: ' ', style: self.style };
+                self.cells[row + self.cursor.0.min(width)..row + width].fill(blank);
+            }
+            'J' if params == "2" => self.cells.fill(BLANK),
+            'm' if params.is_empty() || params == "0" => self.style = 0,
+            'm' => {
+                let escape = format!("{}\x1B[{}m", self.styles[self.style], params);
+                self.style = match self.styles.iter().position(|s| *s == escape) {
+                    Some(index) => index,
+                    None => {
+                        self.styles.push(escape);
+                        self.styles.len() - 1
+                    }
+                };
+            }
+            // Cursor visibility and the like don't change any cells
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Write for Screen {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for ch in text.chars() {
+            let Some(escape) = self.escape.as_mut() else {
+                if ch == '\x1B' {
+                    self.escape = Some(String::new());
+                } else {
+                    self.put(ch);
+                }
+                continue;
+            };
+            if escape.is_empty() && ch == '[' || !escape.is_empty() && !('\x40'..='\x7E').contains(&ch) {
+                // Parameters and intermediates until the final byte
+                escape.push(ch);
+                continue;
+            }
+            // Escapes other than CSI, like saving the cursor, change no cells
+            if let Some(escape) = self.escape.take().filter(|e| !e.is_empty()) {
+                self.control(&escape[1..], ch);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    display::{save_screenshot, screenshot_path, HEIGHT, LORES, WIDTH},
+    expr::Expr,
+    session::{parse_address, Command, Session, StopReason},
+    symbols::SymbolTable,
+    theme::Theme,
+};
+
+///Instructions a single command may run before it is paused, so scripts can't hang CI
+pub const RUN_LIMIT: u64 = 10_000_000;
+///Bytes dumped by `x` when no length is given
+const DUMP_LEN: usize = 16;
+
+///Commands only available in scripts and the REPL, on top of the debugger's
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ScriptCommand {
+    Debugger(Command),
+    Print(Expr),            //Evaluate and show an expression
+    Assert(Expr),           //Fail the script unless the expression is true
+    Dump(u16, usize),       //Hex dump of memory
+    Registers,
+    Backtrace,
+    Screen,                 //The display as text
+    Echo(String),
+    Limit(u64),             //Change the per-command run limit
+}
+
+impl ScriptCommand {
+    pub fn parse(line: &str, symbols: Option<&SymbolTable>) -> Result<ScriptCommand, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match name {
+            "print" => Ok(ScriptCommand::Print(Expr::parse(rest)?)),
+            "assert" => Ok(ScriptCommand::Assert(Expr::parse(rest)?)),
+            "x" | "dump" => {
+                let mut args = rest.split_whitespace();
+                let addr = parse_address(args.next().ok_or("dump needs an address")?, symbols)?;
+                let len = match args.next() {
+                    Some(len) => len.parse().map_err(|_| format!("bad length `{}`", len))?,
+                    None => DUMP_LEN,
+                };
+                Ok(ScriptCommand::Dump(addr, len))
+            }
+            "regs" | "registers" => Ok(ScriptCommand::Registers),
+            "bt" | "backtrace" => Ok(ScriptCommand::Backtrace),
+            "screen" => Ok(ScriptCommand::Screen),
+            "echo" => Ok(ScriptCommand::Echo(rest.to_owned())),
+            "limit" => rest.parse().map(ScriptCommand::Limit).map_err(|_| format!("bad run limit `{}`", rest)),
+            _ => Command::parse(line, symbols).map(ScriptCommand::Debugger),
+        }
+    }
+}
+
+///Runs debugger commands without the terminal UI, writing results as plain text
+pub struct ScriptRunner<W: Write> {
+    session: Session,
+    symbols: Option<SymbolTable>,
+    out: W,
+    limit: u64,
+    failures: u32,  //Failed assertions and bad commands
+    quit: bool,
+    theme: Theme,   //Colors screenshots are saved in
+}
+
+impl<W: Write> ScriptRunner<W> {
+    pub fn new(session: Session, symbols: Option<SymbolTable>, out: W) -> Self {
+        Self { session, symbols, out, limit: RUN_LIMIT, failures: 0, quit: false, theme: Theme::default() }
+    }
+
+    ///Saves screenshots in `theme` rather than white on black
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn into_session(self) -> Session {
+        self.session
+    }
+
+    pub fn into_session_and_output(self) -> (Session, W) {
+        (self.session, self.out)
+    }
+
+    ///Runs every line of `input` until it ends or a `quit`. With `prompt` set each command is
+    ///preceded by a prompt, otherwise the command itself is echoed so logs read like a session
+    pub fn run(&mut self, input: impl BufRead, prompt: bool) -> io::Result<u32> {
+        let mut lines = input.lines().enumerate();
+        loop {
+            if prompt {
+                write!(self.out, "(skye) ")?;
+                self.out.flush()?;
+            }
+            let Some((number, line)) = lines.next() else { break };
+            let line = line?;
+            let text = line.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            if !prompt {
+                writeln!(self.out, "(skye) {}", text)?;
+            }
+            if let Err(err) = self.run_line(text) {
+                self.failures += 1;
+                writeln!(self.out, "line {}: {}", number + 1, err)?;
+            }
+            if self.quit {
+                break;
+            }
+        }
+        Ok(self.failures)
+    }
+
+    ///Runs one command, returning an error for bad input and failed assertions
+    pub fn run_line(&mut self, line: &str) -> Result<(), String> {
+        let command = ScriptCommand::parse(line, self.symbols.as_ref())?;
+        let output = self.execute(command)?;
+        if !output.is_empty() {
+            writeln!(self.out, "{}", output).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, command: ScriptCommand) -> Result<String, String> {
+        let cpu = self.session.cpu();
+        let output = match command {
+            ScriptCommand::Debugger(Command::Quit) => {
+                self.quit = true;
+                String::new()
+            }
+            ScriptCommand::Debugger(Command::Screenshot(path, scale)) => {
+                let path = path.unwrap_or_else(|| screenshot_path(self.session.cycles()));
+                save_screenshot(&path, LORES, cpu.dump_vram(), &self.theme, scale).map_err(|e| format!("cannot save {}: {}", path, e))?;
+                format!("saved {}", path)
+            }
+            ScriptCommand::Debugger(command) => self.run_command(&command),
+            ScriptCommand::Print(expr) => {
+                let value = expr.eval(cpu);
+                format!("{} = {:#X} ({})", expr, value, value)
+            }
+            ScriptCommand::Assert(expr) => {
+                let value = expr.eval(cpu);
+                if value == 0 {
+                    return Err(format!("assertion failed: {} at {}", expr, self.describe_address(cpu.dump_pc())));
+                }
+                String::new()
+            }
+            ScriptCommand::Dump(addr, len) => {
+                let memory = cpu.dump_memory();
+                let end = (addr as usize).saturating_add(len).min(memory.len());
+                let rows: Vec<String> = (addr as usize..end)
+                    .step_by(16)
+                    .map(|row| {
+                        let bytes: Vec<String> = memory[row..end.min(row + 16)].iter().map(|b| format!("{:02X}", b)).collect();
+                        format!("{:#05X}: {}", row, bytes.join(" "))
+                    })
+                    .collect();
+                rows.join("\n")
+            }
+            ScriptCommand::Registers => {
+                let registers: Vec<String> = cpu.dump_registers().iter().enumerate().map(|(i, v)| format!("V{:X}={:02X}", i, v)).collect();
+                let (timer, sound_timer) = cpu.dump_clock();
+                format!(
+                    "{}\nI={:03X} PC={} SP={} DT={:02X} ST={:02X}",
+                    registers.join(" "),
+                    cpu.dump_large_register(),
+                    self.describe_address(cpu.dump_pc()),
+                    cpu.dump_stack().head,
+                    timer,
+                    sound_timer
+                )
+            }
+            ScriptCommand::Backtrace => {
+                let mut rows = vec![format!("#0 {}", self.describe_address(cpu.dump_pc()))];
+                for (depth, frame) in self.session.backtrace().iter().enumerate() {
+                    let (site, target) = (self.describe_address(frame.call_site), self.describe_address(frame.target));
+                    rows.push(format!("#{} {} calls {}", depth + 1, site, target));
+                }
+                rows.join("\n")
+            }
+            ScriptCommand::Screen => {
+                let vram = cpu.dump_vram();
+                let rows: Vec<String> = (0..HEIGHT)
+                    .map(|y| (0..WIDTH).map(|x| if vram[x + y * WIDTH] == 1 { '#' } else { '.' }).collect())
+                    .collect();
+                rows.join("\n")
+            }
+            ScriptCommand::Echo(text) => text,
+            ScriptCommand::Limit(limit) => {
+                self.limit = limit;
+                String::new()
+            }
+        };
+        Ok(output)
+    }
+
+    ///Executes a debugger command and runs until it stops, describing why
+    fn run_command(&mut self, command: &Command) -> String {
+        let mut stop = self.session.execute(command);
+        let mut cycles = 0;
+        while stop.is_none() && self.session.is_running() {
+            if cycles == self.limit {
+                self.session.execute(&Command::Pause);
+                return format!("stopped after {} instructions at {}", cycles, self.describe_address(self.session.cpu().dump_pc()));
+            }
+            stop = self.session.tick().stop;
+            cycles += 1;
+        }
+        let Some(stop) = stop else {
+            return String::new();
+        };
+        let pc = self.session.cpu().dump_pc();
+        match stop {
+            StopReason::Fault(_) | StopReason::Halted => stop.describe(self.symbols.as_ref()),
+            _ => format!("{} at {}", stop.describe(self.symbols.as_ref()), self.describe_address(pc)),
+        }
+    }
+
+    fn describe_address(&self, addr: u16) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{:#05X}", addr),
+        }
+    }
+}
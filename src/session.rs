@@ -0,0 +1,594 @@
+use crate::{
+    breakpoints::{BreakKind, Breakpoint, BreakpointSet},
+    coverage::Coverage,
+    cpu::{Chip8, Fault},
+    debugger::{LayoutCommand, MemoryCommand, SpriteCommand},
+    display::{MAX_SCREENSHOT_SCALE, SCREENSHOT_SCALE},
+    expr::Expr,
+    history::History,
+    parser::{DataType, OpCode, OpCodeIdentity},
+    profile::Profiler,
+    symbols::SymbolTable,
+    trace::{TraceState, Tracer},
+    watchpoints::{RegSnapshot, WatchHit, WatchKind, WatchReg, WatchpointSet},
+};
+
+///Commands accepted by the interactive debugger
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Step(u32),      //Execute N instructions then pause
+    StepOver,       //Step, running a called subroutine to completion
+    StepOut,        //Run until the current subroutine returns
+    Continue,       //Run freely
+    Pause,          //Stop running
+    RunTo(u16),     //Run until the PC reaches an address
+    StepBack(u32),  //Undo N instructions
+    ReverseContinue,        //Run backwards to the previous breakpoint
+    ReverseUntil(WatchReg), //Run backwards to the instruction that last changed a register
+    Seek(u64),      //Move to a cycle in the recorded history
+    Keys(u16),      //Hold down exactly these keypad keys, bit N is key N
+    Reset,          //Restart the program from scratch
+    Break(BreakKind, Option<Expr>, bool),   //Add a breakpoint, temporary if the flag is set
+    Delete(u32),    //Remove a breakpoint
+    Enable(u32, bool),
+    Watch(WatchKind),
+    Unwatch(u32),
+    Memory(MemoryCommand),  //Handled by the debugger view
+    Layout(LayoutCommand),  //Handled by the debugger view
+    Sprites(SpriteCommand), //Handled by the debugger view
+    Screenshot(Option<String>, usize),  //Saved by the front end with its palette, at a scale
+    Quit,
+}
+
+///Parses a number or label, hex with a `0x` prefix and decimal otherwise
+pub fn parse_address(token: &str, symbols: Option<&SymbolTable>) -> Result<u16, String> {
+    if let Some(hex) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|_| format!("bad address `{}`", token));
+    }
+    if let Ok(value) = token.parse() {
+        return Ok(value);
+    }
+    symbols
+        .and_then(|s| s.address_of(token))
+        .ok_or_else(|| format!("unknown label `{}`", token))
+}
+
+impl Command {
+    pub fn parse(line: &str, symbols: Option<&SymbolTable>) -> Result<Command, String> {
+        let mut tokens = line.split_whitespace();
+        let Some(name) = tokens.next() else {
+            return Err("empty command".to_owned());
+        };
+        let arg = tokens.next();
+        let rest = line.trim_start()[name.len()..].trim();
+        let id = || arg.and_then(|a| a.trim_start_matches('#').parse().ok()).ok_or("needs a breakpoint number");
+        match name {
+            "s" | "step" => {
+                let count = match arg {
+                    Some(n) => n.parse().map_err(|_| format!("bad step count `{}`", n))?,
+                    None => 1,
+                };
+                Ok(Command::Step(count))
+            }
+            "n" | "next" | "over" => Ok(Command::StepOver),
+            "f" | "finish" | "out" => Ok(Command::StepOut),
+            "c" | "continue" => Ok(Command::Continue),
+            "p" | "pause" => Ok(Command::Pause),
+            "r" | "run" | "until" => {
+                let addr = arg.ok_or("run needs an address")?;
+                Ok(Command::RunTo(parse_address(addr, symbols)?))
+            }
+            "rs" | "back" | "reverse-step" => {
+                let count = match arg {
+                    Some(n) => n.parse().map_err(|_| format!("bad step count `{}`", n))?,
+                    None => 1,
+                };
+                Ok(Command::StepBack(count))
+            }
+            "rc" | "reverse-continue" => Ok(Command::ReverseContinue),
+            "ru" | "reverse-until" => {
+                let reg = arg.and_then(WatchReg::parse).ok_or("reverse-until needs a register")?;
+                Ok(Command::ReverseUntil(reg))
+            }
+            "seek" => {
+                let cycle = arg.and_then(|c| c.parse().ok()).ok_or("seek needs a cycle number")?;
+                Ok(Command::Seek(cycle))
+            }
+            "reset" => Ok(Command::Reset),
+            "k" | "keys" => {
+                // Hex digits of the keys to hold, none releases them all
+                let mut keys = 0u16;
+                for token in line.split_whitespace().skip(1) {
+                    let key = u8::from_str_radix(token, 16).ok().filter(|k| *k < 16).ok_or_else(|| format!("bad key `{}`, use 0 to F", token))?;
+                    keys |= 1 << key;
+                }
+                Ok(Command::Keys(keys))
+            }
+            "b" | "break" | "tb" | "tbreak" => {
+                let (kind, condition) = Breakpoint::parse(rest, symbols)?;
+                Ok(Command::Break(kind, condition, name.starts_with('t')))
+            }
+            "d" | "delete" => Ok(Command::Delete(id()?)),
+            "enable" => Ok(Command::Enable(id()?, true)),
+            "disable" => Ok(Command::Enable(id()?, false)),
+            "watch" => Ok(Command::Watch(WatchKind::parse(rest, false, true, symbols)?)),
+            "rwatch" => Ok(Command::Watch(WatchKind::parse(rest, true, false, symbols)?)),
+            "awatch" => Ok(Command::Watch(WatchKind::parse(rest, true, true, symbols)?)),
+            "unwatch" => {
+                let id = arg.and_then(|a| a.trim_start_matches('w').parse().ok()).ok_or("needs a watchpoint number")?;
+                Ok(Command::Unwatch(id))
+            }
+            "m" | "mem" => {
+                let count = tokens.next().map_or(Ok(1), |n| n.parse::<i32>().map_err(|_| format!("bad row count `{}`", n)));
+                let command = match arg {
+                    None | Some("follow") => MemoryCommand::Follow,
+                    Some("down") => MemoryCommand::Scroll(count?),
+                    Some("up") => MemoryCommand::Scroll(-count?),
+                    Some("sprite") => MemoryCommand::Sprite(true),
+                    Some("hex") => MemoryCommand::Sprite(false),
+                    Some(addr) => MemoryCommand::Pin(parse_address(addr, symbols)?),
+                };
+                Ok(Command::Memory(command))
+            }
+            "sprites" => {
+                let count = tokens.next().map_or(Ok(1), |n| n.parse::<i32>().map_err(|_| format!("bad sprite count `{}`", n)));
+                let command = match arg {
+                    None | Some("follow") => SpriteCommand::Follow,
+                    Some("down") => SpriteCommand::Scroll(count?),
+                    Some("up") => SpriteCommand::Scroll(-count?),
+                    Some("height") => match count? {
+                        height @ 1..=15 => SpriteCommand::Height(height as u8),
+                        height => return Err(format!("sprite height {} is not 1 to 15", height)),
+                    },
+                    Some("schip") => SpriteCommand::Wide(true),
+                    Some("chip8") => SpriteCommand::Wide(false),
+                    Some("off") => SpriteCommand::Hide,
+                    Some(addr) => SpriteCommand::Pin(parse_address(addr, symbols)?),
+                };
+                Ok(Command::Sprites(command))
+            }
+            "layout" => match arg {
+                Some("compact") => Ok(Command::Layout(LayoutCommand::Compact(true))),
+                Some("full") => Ok(Command::Layout(LayoutCommand::Compact(false))),
+                _ => Err("layout needs `compact` or `full`".to_owned()),
+            },
+            "tab" => Ok(Command::Layout(LayoutCommand::NextTab)),
+            "ss" | "screenshot" => {
+                // Either argument may be left out, a number is the scale
+                let mut path = None;
+                let mut scale = SCREENSHOT_SCALE;
+                for token in [arg, tokens.next()].into_iter().flatten() {
+                    match token.parse() {
+                        Ok(n @ 1..=MAX_SCREENSHOT_SCALE) => scale = n,
+                        Ok(_) => return Err(format!("screenshot scale must be 1 to {}", MAX_SCREENSHOT_SCALE)),
+                        Err(_) => path = Some(token.to_owned()),
+                    }
+                }
+                Ok(Command::Screenshot(path, scale))
+            }
+            "q" | "quit" => Ok(Command::Quit),
+            other => Err(format!("unknown command `{}`", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RunState {
+    Paused,
+    Running,
+    Stepping(u32),
+    RunningTo(u16),
+    Returning(u8),  //Running until the stack depth drops to this
+}
+
+///Why execution stopped
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum StopReason {
+    Stepped,
+    Paused,
+    Reached(u16),
+    Breakpoint(u32),
+    Watch(WatchHit),
+    Halted,         //PC ran past the end of the program
+    HistoryStart,   //Ran backwards into the oldest recorded cycle
+    Changed { reg: WatchReg, old: u16, new: u16 },  //Found by `ReverseUntil`, stopped before the change
+    Fault(Fault),
+}
+
+impl StopReason {
+    ///Human readable reason, with addresses symbolized when symbols are given
+    pub fn describe(&self, symbols: Option<&SymbolTable>) -> String {
+        let describe_address = |addr: u16| match symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{:#05X}", addr),
+        };
+        match self {
+            StopReason::Stepped => "step done".to_owned(),
+            StopReason::Paused => "paused by user".to_owned(),
+            StopReason::Reached(addr) => format!("reached {}", describe_address(*addr)),
+            StopReason::Breakpoint(id) => format!("hit breakpoint #{}", id),
+            StopReason::Watch(hit) => {
+                let mnemonic = OpCode::decode(hit.op_code).map(|oc| oc.mnemonic()).unwrap_or_default();
+                format!("{} by {} {:04X} {}", hit, describe_address(hit.pc), hit.op_code, mnemonic)
+            }
+            StopReason::Halted => "program ended".to_owned(),
+            StopReason::HistoryStart => "reached the start of the recorded history".to_owned(),
+            StopReason::Changed { reg, old, new } => format!("next instruction changes {} {:#X} -> {:#X}", reg, old, new),
+            StopReason::Fault(fault) => format!("CPU fault: {}", fault.error),
+        }
+    }
+}
+
+///Result of one `Session::tick`
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct Tick {
+    pub frame_changed: bool,
+    pub stop: Option<StopReason>,
+}
+
+///One active subroutine call, innermost first in a backtrace
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Frame {
+    pub call_site: u16,
+    pub target: u16,
+}
+
+///Sprite drawn by a `DrawDispRRC`, with the values it was drawn from
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Draw {
+    pub pc: u16,
+    pub addr: u16,  //`I` when it was drawn
+    pub x: u8,
+    pub y: u8,
+    pub height: u8,
+}
+
+impl Draw {
+    ///The draw the instruction at the PC is about to make, if it is one
+    fn next(program: &[OpCode], cpu: &Chip8) -> Option<Draw> {
+        let pc = cpu.dump_pc();
+        let oc = program.get(pc as usize).filter(|oc| oc.oc_id == OpCodeIdentity::DrawDispRRC)?;
+        let DataType::XYN { x, y, constant } = oc.get_data() else { return None };
+        let registers = cpu.dump_registers();
+        Some(Draw { pc, addr: cpu.dump_large_register(), x: registers[x as usize], y: registers[y as usize], height: constant })
+    }
+}
+
+///Owns the emulated machine and decides when it runs
+pub struct Session {
+    cpu: Chip8,
+    program: Vec<OpCode>,
+    state: RunState,
+    breakpoints: BreakpointSet,
+    watchpoints: WatchpointSet,
+    resumed: bool,  //Set when execution continues, so a breakpoint at the PC doesn't fire again
+    cycles: u64,    //Instructions executed since the last reset
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    last_draw: Option<Draw>,
+    history: History,
+    seed: Option<u64>,  //Fixed random seed, reused on reset
+}
+
+impl Session {
+    pub fn new(program: Vec<OpCode>) -> Self {
+        Self::start(program, None)
+    }
+
+    ///Session whose random numbers are the same on every run
+    pub fn with_seed(program: Vec<OpCode>, seed: u64) -> Self {
+        Self::start(program, Some(seed))
+    }
+
+    fn start(program: Vec<OpCode>, seed: Option<u64>) -> Self {
+        let cpu = Self::boot(&program, seed);
+        Self {
+            history: History::new(&cpu),
+            cpu,
+            program,
+            state: RunState::Paused,
+            breakpoints: Default::default(),
+            watchpoints: Default::default(),
+            resumed: false,
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            last_draw: None,
+            seed,
+        }
+    }
+
+    fn boot(program: &[OpCode], seed: Option<u64>) -> Chip8 {
+        match seed {
+            Some(seed) => Chip8::with_seed(program.to_vec(), seed),
+            None => Chip8::new(program.to_vec()),
+        }
+    }
+
+    pub fn breakpoints(&self) -> &BreakpointSet {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BreakpointSet {
+        &mut self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &WatchpointSet {
+        &self.watchpoints
+    }
+
+    pub fn cpu(&self) -> &Chip8 {
+        &self.cpu
+    }
+
+    ///The machine for editing, which starts a new branch of history
+    pub fn cpu_mut(&mut self) -> &mut Chip8 {
+        self.history.invalidate();
+        &mut self.cpu
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    ///Sets the pressed keys, recorded so replays see them at the same cycle
+    pub fn set_keys(&mut self, keys: u16) {
+        if keys != self.cpu.dump_keys() {
+            self.history.set_keys(self.cycles, keys);
+            self.cpu.set_keys(keys);
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    ///Starts writing every executed instruction to `tracer`, replacing the previous one
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    ///Starts counting executed instructions into `profiler`, replacing the previous one
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    ///Starts recording which instructions run into `coverage`, replacing the previous one
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    ///Most recent sprite drawn
+    pub fn last_draw(&self) -> Option<Draw> {
+        self.last_draw
+    }
+
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state != RunState::Paused
+    }
+
+    ///Active calls from the stack's return addresses, innermost first
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let stack = self.cpu.dump_stack();
+        stack.data[..stack.head as usize]
+            .iter()
+            .rev()
+            .map(|ret| {
+                let call_site = ret.wrapping_sub(1);
+                let target = match self.program.get(call_site as usize).map(|oc| oc.get_data()) {
+                    Some(DataType::NNN { address }) => address,
+                    _ => call_site,
+                };
+                Frame { call_site, target }
+            })
+            .collect()
+    }
+
+    ///Applies a command, returns a stop reason for commands that pause immediately
+    pub fn execute(&mut self, command: &Command) -> Option<StopReason> {
+        self.resumed = matches!(command, Command::Step(_) | Command::StepOver | Command::StepOut | Command::Continue | Command::RunTo(_));
+        match *command {
+            Command::Step(0) => None,
+            Command::Step(n) => {
+                self.state = RunState::Stepping(n);
+                None
+            }
+            // Stopping once the depth is back where it started steps over calls and stops after
+            // anything else, including a return
+            Command::StepOver => {
+                self.state = RunState::Returning(self.cpu.dump_stack().head);
+                None
+            }
+            Command::StepOut => {
+                match self.cpu.dump_stack().head.checked_sub(1) {
+                    Some(depth) => self.state = RunState::Returning(depth),
+                    None => self.resumed = false,
+                }
+                None
+            }
+            Command::Continue => {
+                self.state = RunState::Running;
+                None
+            }
+            Command::Pause => {
+                let was_running = self.is_running();
+                self.state = RunState::Paused;
+                was_running.then_some(StopReason::Paused)
+            }
+            Command::RunTo(addr) => {
+                self.state = RunState::RunningTo(addr);
+                None
+            }
+            Command::StepBack(0) => None,
+            Command::StepBack(n) => {
+                let target = self.cycles.saturating_sub(n as u64).max(self.history.earliest());
+                let reason = if self.cycles.checked_sub(n as u64) == Some(target) { StopReason::Stepped } else { StopReason::HistoryStart };
+                Some(self.rewind(target, reason))
+            }
+            Command::ReverseContinue => {
+                self.history.sync(self.cycles, &self.cpu);
+                let breakpoints = &self.breakpoints;
+                match self.history.search_back(self.cycles, |_, cpu| breakpoints.peek(cpu).is_some()) {
+                    Some(cycle) => {
+                        self.rewind(cycle, StopReason::Paused);
+                        let id = self.breakpoints.peek(&self.cpu).unwrap_or_default();
+                        Some(StopReason::Breakpoint(id))
+                    }
+                    None => Some(self.rewind(self.history.earliest(), StopReason::HistoryStart)),
+                }
+            }
+            Command::ReverseUntil(reg) => {
+                self.history.sync(self.cycles, &self.cpu);
+                // Matches the cycle right after the change, the instruction before it made the change
+                let mut previous: Option<(u64, u16)> = None;
+                let mut change = (0, 0);
+                let found = self.history.search_back(self.cycles + 1, |cycle, cpu| {
+                    let value = reg.read(cpu);
+                    let changed = previous.is_some_and(|(c, old)| c + 1 == cycle && old != value);
+                    if changed {
+                        change = (previous.map_or(0, |(_, old)| old), value);
+                    }
+                    previous = Some((cycle, value));
+                    changed
+                });
+                match found {
+                    Some(cycle) => Some(self.rewind(cycle - 1, StopReason::Changed { reg, old: change.0, new: change.1 })),
+                    None => Some(self.rewind(self.history.earliest(), StopReason::HistoryStart)),
+                }
+            }
+            Command::Seek(cycle) => Some(self.rewind(cycle.min(self.history.latest()), StopReason::Stepped)),
+            Command::Keys(keys) => {
+                self.set_keys(keys);
+                None
+            }
+            Command::Reset => {
+                self.cpu = Self::boot(&self.program, self.seed);
+                self.history = History::new(&self.cpu);
+                self.state = RunState::Paused;
+                self.cycles = 0;
+                self.last_draw = None;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.unwind();
+                }
+                None
+            }
+            Command::Break(ref kind, ref condition, temporary) => {
+                self.breakpoints.add(kind.clone(), condition.clone(), temporary);
+                None
+            }
+            Command::Delete(id) => {
+                self.breakpoints.remove(id);
+                None
+            }
+            Command::Enable(id, enabled) => {
+                self.breakpoints.set_enabled(id, enabled);
+                None
+            }
+            Command::Watch(kind) => {
+                self.watchpoints.add(kind);
+                None
+            }
+            Command::Unwatch(id) => {
+                self.watchpoints.remove(id);
+                None
+            }
+            Command::Memory(_) | Command::Layout(_) | Command::Sprites(_) | Command::Screenshot(..) | Command::Quit => None,
+        }
+    }
+
+    ///Executes one instruction if the session is running
+    pub fn tick(&mut self) -> Tick {
+        if !self.is_running() {
+            return Tick::default();
+        }
+        self.history.before_cycle(self.cycles, &mut self.cpu);
+        if !std::mem::take(&mut self.resumed) {
+            if let Some(id) = self.breakpoints.check(&self.cpu) {
+                return self.stop(StopReason::Breakpoint(id), false);
+            }
+        }
+        let pc = self.cpu.dump_pc();
+        let before = RegSnapshot::take(&self.cpu);
+        let trace_before = self.tracer.is_some().then(|| TraceState::take(&self.cpu));
+        let draw = Draw::next(&self.program, &self.cpu);
+        let frame_changed = match self.cpu.try_cycle() {
+            Ok(Some(res)) => res.0.is_some(),
+            Ok(None) => return self.stop(StopReason::Halted, false),
+            Err(fault) => return self.stop(StopReason::Fault(fault), false),
+        };
+        let op_code = self.program[pc as usize].op_code;
+        if draw.is_some() {
+            self.last_draw = draw;
+        }
+        self.history.after_cycle(self.cycles, pc, op_code);
+        self.cycles += 1;
+        if let (Some(tracer), Some(trace_before)) = (&mut self.tracer, trace_before) {
+            tracer.record(self.cycles, pc, &self.program[pc as usize], &trace_before, &self.cpu);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, &self.program[pc as usize], &self.cpu);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, &self.program[pc as usize], &self.cpu);
+        }
+        if !self.watchpoints.is_empty() {
+            if let Some(hit) = self.watchpoints.check(pc, op_code, &before, &self.cpu) {
+                return self.stop(StopReason::Watch(hit), frame_changed);
+            }
+        }
+        match self.state {
+            RunState::Stepping(1) => self.stop(StopReason::Stepped, frame_changed),
+            RunState::Stepping(n) => {
+                self.state = RunState::Stepping(n - 1);
+                Tick { frame_changed, stop: None }
+            }
+            RunState::RunningTo(addr) if self.cpu.dump_pc() == addr => self.stop(StopReason::Reached(addr), frame_changed),
+            RunState::Returning(depth) if self.cpu.dump_stack().head <= depth => self.stop(StopReason::Stepped, frame_changed),
+            _ => Tick { frame_changed, stop: None },
+        }
+    }
+
+    ///Replaces the machine with its state at `cycle` from the recorded history
+    fn rewind(&mut self, cycle: u64, reason: StopReason) -> StopReason {
+        self.history.sync(self.cycles, &self.cpu);
+        let earliest = self.history.earliest();
+        let reason = if cycle < earliest { StopReason::HistoryStart } else { reason };
+        let (reached, cpu) = self.history.restore(cycle);
+        self.cpu = cpu;
+        self.cycles = reached;
+        self.state = RunState::Paused;
+        // The latest draw before the new position, found by replaying
+        let mut last_draw = None;
+        self.history.search_back(reached, |_, cpu| {
+            let draw = Draw::next(&self.program, cpu);
+            if draw.is_some() {
+                last_draw = draw;
+            }
+            draw.is_some()
+        });
+        self.last_draw = last_draw;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.unwind();
+        }
+        reason
+    }
+
+    fn stop(&mut self, reason: StopReason, frame_changed: bool) -> Tick {
+        self.state = RunState::Paused;
+        Tick { frame_changed, stop: Some(reason) }
+    }
+}
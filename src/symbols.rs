@@ -0,0 +1,229 @@
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+///Source position an address was assembled from
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+///Range of addresses holding data rather than code, `end` is exclusive
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DataRegion {
+    pub start: u16,
+    pub end: u16,
+    pub name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Io(err) => write!(f, "{}", err),
+            SymbolError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for SymbolError {
+    fn from(err: std::io::Error) -> Self {
+        SymbolError::Io(err)
+    }
+}
+
+///Debug information written next to a ROM.
+///
+///The file is plain text with one record per line:
+///```text
+///line  0x004 game.asm 57
+///label 0x000 draw_player
+///alias player_x V3
+///data  0x040 0x048 sprites
+///```
+///Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct SymbolTable {
+    lines: BTreeMap<u16, SourceLocation>,
+    labels: BTreeMap<u16, String>,
+    aliases: Vec<(String, String)>,
+    data: Vec<DataRegion>,
+}
+
+fn parse_addr(token: &str) -> Option<u16> {
+    let digits = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")).unwrap_or(token);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    ///Builds a table for a hex listing where every line holds one word
+    pub fn from_listing(file: &str, text: &str) -> Self {
+        let mut table = Self::new();
+        for (i, _) in text.lines().enumerate() {
+            table.add_line(i as u16, file, i as u32 + 1);
+        }
+        table
+    }
+
+    ///Symbols for the program at `file`: its `.sym` file when there is one, otherwise a hex
+    ///listing is its own source and a binary has none. A `.sym` file that doesn't parse is an
+    ///`InvalidData` error naming it
+    pub fn for_program(file: &str) -> io::Result<Self> {
+        let sym_path = Path::new(file).with_extension("sym");
+        if sym_path.exists() {
+            return Self::load(&sym_path).map_err(|err| match err {
+                SymbolError::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", sym_path.display(), err)),
+            });
+        }
+        if file.ends_with(".ch8") {
+            return Ok(Self::new());
+        }
+        let contents = fs::read_to_string(file)?;
+        let name = Path::new(file).file_name().map_or(file.into(), |n| n.to_string_lossy());
+        Ok(Self::from_listing(&name, &contents))
+    }
+
+    pub fn add_line(&mut self, addr: u16, file: &str, line: u32) {
+        self.lines.insert(addr, SourceLocation { file: file.to_owned(), line });
+    }
+
+    pub fn add_label(&mut self, addr: u16, name: &str) {
+        self.labels.entry(addr).or_insert_with(|| name.to_owned());
+    }
+
+    pub fn add_alias(&mut self, name: &str, target: &str) {
+        self.aliases.push((name.to_owned(), target.to_owned()));
+    }
+
+    pub fn add_data_region(&mut self, start: u16, end: u16, name: Option<&str>) {
+        self.data.push(DataRegion { start, end, name: name.map(str::to_owned) });
+    }
+
+    pub fn source(&self, addr: u16) -> Option<&SourceLocation> {
+        self.lines.get(&addr)
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = (u16, &SourceLocation)> {
+        self.lines.iter().map(|(addr, loc)| (*addr, loc))
+    }
+
+    ///Closest label at or below `addr` together with the offset from it
+    pub fn label(&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels
+            .range(..=addr)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), addr - start))
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    pub fn address_of(&self, label: &str) -> Option<u16> {
+        self.labels.iter().find(|(_, name)| *name == label).map(|(addr, _)| *addr)
+    }
+
+    pub fn alias(&self, name: &str) -> Option<&str> {
+        self.aliases.iter().find(|(alias, _)| alias == name).map(|(_, target)| target.as_str())
+    }
+
+    pub fn data_regions(&self) -> &[DataRegion] {
+        &self.data
+    }
+
+    pub fn data_region(&self, addr: u16) -> Option<&DataRegion> {
+        self.data.iter().find(|region| region.start <= addr && addr < region.end)
+    }
+
+    ///Formats an address as `label+offset (file:line)`, falling back to hex
+    pub fn describe(&self, addr: u16) -> String {
+        let mut out = match self.label(addr) {
+            Some((name, 0)) => name.to_owned(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:#05X}", addr),
+        };
+        if let Some(loc) = self.source(addr) {
+            out.push_str(&format!(" ({}:{})", loc.file, loc.line));
+        }
+        out
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut table = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let err = |message: &str| SymbolError::Parse { line: line_no, message: message.to_owned() };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let addr = |idx: usize| tokens.get(idx).and_then(|t| parse_addr(t)).ok_or_else(|| err("bad address"));
+            match tokens[0] {
+                "line" => {
+                    let file = tokens.get(2).ok_or_else(|| err("missing file"))?;
+                    let src_line = tokens.get(3).and_then(|t| t.parse().ok()).ok_or_else(|| err("bad line number"))?;
+                    table.add_line(addr(1)?, file, src_line);
+                }
+                "label" => {
+                    let name = tokens.get(2).ok_or_else(|| err("missing label name"))?;
+                    table.add_label(addr(1)?, name);
+                }
+                "alias" => {
+                    let name = tokens.get(1).ok_or_else(|| err("missing alias name"))?;
+                    let target = tokens.get(2).ok_or_else(|| err("missing alias target"))?;
+                    table.add_alias(name, target);
+                }
+                "data" => {
+                    let (start, end) = (addr(1)?, addr(2)?);
+                    if end < start {
+                        return Err(err("data region ends before it starts"));
+                    }
+                    table.add_data_region(start, end, tokens.get(3).copied());
+                }
+                other => return Err(err(&format!("unknown record `{}`", other))),
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SymbolError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (addr, loc) in &self.lines {
+            writeln!(f, "line {:#05X} {} {}", addr, loc.file, loc.line)?;
+        }
+        for (addr, name) in &self.labels {
+            writeln!(f, "label {:#05X} {}", addr, name)?;
+        }
+        for (name, target) in &self.aliases {
+            writeln!(f, "alias {} {}", name, target)?;
+        }
+        for region in &self.data {
+            write!(f, "data {:#05X} {:#05X}", region.start, region.end)?;
+            if let Some(name) = &region.name {
+                write!(f, " {}", name)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
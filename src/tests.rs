@@ -1,6 +1,30 @@
 #[cfg(test)]
 mod tests {
+    use crate::analysis::ControlFlowGraph;
+    use crate::coverage::{Coverage, CoverageSummary, SkipCount};
+    use crate::cpu::{AccessKind, Chip8, MemAccess};
+    use crate::dap::{read_message, write_message, DapServer};
+    use crate::display::{backend_from_name, encode_pbm, save_screenshot, scale_frame, Display, DisplayBackend, DisplayEvent, ImageDisplay, ImageFormat, Mode, RecordingDisplay, TextStyle, LORES};
+    use crate::debugger::{code_window_start, Debugger, CODE_ROWS, MemoryAnchor, MemoryCommand, MemoryView, SpriteCommand};
+    use crate::expr::Expr;
+    use crate::fastrand::Rand;
+    use crate::gdb::{write_packet, GdbStub};
+    use crate::json::Json;
+    use crate::layout::{Layout, Panel};
+    use crate::lint::{lint, LintKind};
     use crate::parser::*;
+    use crate::png::{adler32, crc32, encode_indexed, zlib_stored};
+    use crate::profile::{Profiler, SubroutineCost};
+    use crate::screen::Screen;
+    use crate::script::ScriptRunner;
+    use crate::session::{Command, Draw, Frame, Session, StopReason};
+    use crate::symbols::SymbolTable;
+    use crate::theme::{ColorDepth, Rgb, Theme};
+    use crate::trace::{first_divergence, TraceFilter, Tracer};
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use crate::watchpoints::{WatchEvent, WatchReg};
+    use crate::writer::{self, HexStyle};
 
     #[test]
     fn test_text() {
@@ -14,5 +38,825 @@ mod tests {
         assert_eq!(ocs,cmpvec);
     }
 
+    #[test]
+    fn test_symbols() {
+        let text = "# game symbols\nline 0x010 game.asm 53\nline 0x014 game.asm 57\nlabel 0x010 draw_player\nalias player_x V3\ndata 0x040 0x048 sprites\n";
+        let symbols = SymbolTable::parse(text).unwrap();
+        assert_eq!(symbols.describe(0x014), "draw_player+4 (game.asm:57)");
+        assert_eq!(symbols.describe(0x010), "draw_player (game.asm:53)");
+        assert_eq!(symbols.describe(0x00F), "0x00F");
+        assert_eq!(symbols.alias("player_x"), Some("V3"));
+        assert!(symbols.data_region(0x047).is_some());
+        assert!(symbols.data_region(0x048).is_none());
+        assert_eq!(SymbolTable::parse(&symbols.to_string()).unwrap(), symbols);
+        assert!(SymbolTable::parse("label zz start").is_err());
+    }
+
+    #[test]
+    fn test_cfg() {
+        // 0: call 4, 1: skip over 2, 2: jump 1, 3: V0 + 1 (reached by the skip), 4: ret, 5: jump V0 + 0 (dead)
+        let program = parse_text("2004\n3000\n1001\n7001\n00EE\nB000".to_owned());
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<u16>>(), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(cfg.subroutines[&4].callers, vec![0]);
+        assert!(cfg.subroutines[&4].returns);
+        assert!(cfg.is_reachable(1) && cfg.is_reachable(3));
+        assert!(!cfg.is_reachable(5));
+        assert_eq!(cfg.computed_jumps, vec![5]);
+        assert!(cfg.to_dot(&program, None).contains("b000 -> b004 [style=dashed, label=call];"));
+    }
+
+    #[test]
+    fn test_lint() {
+        // 0: I = 0x300, 1: call 4, 2: dump V0 to I, 3: ret, 4: draw, 5: ret
+        let program = parse_text("A300\n2004\nF055\n00EE\nD005\n00EE".to_owned());
+        let diagnostics = lint(&program, None);
+        let found = |addr: u16, kind: LintKind| diagnostics.iter().any(|d| d.addr == addr && d.kind == kind);
+        assert!(found(3, LintKind::ReturnOutsideSub));
+        assert!(!found(5, LintKind::ReturnOutsideSub));
+        assert!(!found(4, LintKind::UnsetAddrReg));
+        assert!(found(2, LintKind::QuirkDependent));
+        assert!(!found(2, LintKind::InterpreterWrite));
+
+        let program = parse_text("D005\n6004\nF055\n1005".to_owned());
+        let diagnostics = lint(&program, None);
+        let found = |addr: u16, kind: LintKind| diagnostics.iter().any(|d| d.addr == addr && d.kind == kind);
+        assert!(found(0, LintKind::UnsetAddrReg));
+        assert!(found(2, LintKind::InterpreterWrite));
+        assert!(found(3, LintKind::BadJumpTarget));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(OpCodeIdentity::DrawDispRRC, DataType::XYN { x: 3, y: 4, constant: 8 }), Ok(0xD348));
+        assert_eq!(encode(OpCodeIdentity::ClrDisp, DataType::None), Ok(0x00E0));
+        assert_eq!(OpCode::decode(0x8AB6).unwrap().get_data(), DataType::X { x: 0xA });
+        assert!(matches!(encode(OpCodeIdentity::SetRC, DataType::XNN { x: 16, constant: 0 }), Err(EncodeError::OutOfRange { .. })));
+        assert!(matches!(encode(OpCodeIdentity::CallMach, DataType::NNN { address: 0x100 }), Err(EncodeError::OutOfRange { .. })));
+        assert!(matches!(encode(OpCodeIdentity::SetRC, DataType::X { x: 1 }), Err(EncodeError::WrongOperands { .. })));
+        // Every decodable word survives a decode/encode/decode round trip
+        for word in 0..=u16::MAX {
+            if let Some(oc) = OpCode::decode(word) {
+                let encoded = OpCode::encode(oc.oc_id, oc.get_data()).unwrap();
+                assert_eq!(encoded.get_data(), oc.get_data());
+                assert_eq!(OpCode::decode(encoded.op_code), Some(encoded));
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = include_str!("../hello.asm");
+        let program = parse_text(source.to_owned());
+        assert_eq!(writer::to_text(&program, HexStyle::detect(source)), source);
+        let binary = writer::to_binary(&program);
+        assert_eq!(binary.len(), program.len() * 2);
+        assert_eq!(parse_binary(&binary).unwrap(), program);
+        assert_eq!(writer::to_binary(&parse_binary(&binary).unwrap()), binary);
+        // Padding and a sprite after the code are kept as data words
+        let rom = [0x00, 0xE0, 0x12, 0x00, 0x00, 0x00, 0xF0, 0x90, 0x90, 0x90, 0xF0, 0x00];
+        let parsed = parse_binary(&rom).unwrap();
+        assert_eq!(parsed[2].oc_id, OpCodeIdentity::Data);
+        assert_eq!(parsed[3].mnemonic(), "DW 0xF090");
+        assert_eq!(writer::to_binary(&parsed), rom);
+        assert!(matches!(parse_binary(&rom[..5]), Err(ParseError::OddLength(5))));
+        assert!(matches!(try_parse_text("6001\n0000"), Err(ParseError::UnknownOpCode { line: 2, op_code: 0 })));
+        assert!(matches!(try_parse_text("60"), Err(ParseError::BadToken { line: 1, .. })));
+        let styled = "00e0\r\n6a0f\r\n";
+        assert_eq!(writer::to_text(&parse_text(styled.to_owned()), HexStyle::detect(styled)), styled);
+        assert!(writer::to_listing(&program, None).starts_with("0x000: 6066  LD V0, 0x66\n"));
+    }
+
+    #[test]
+    fn test_add_wraps() {
+        // 0: V0 = 0xFF, 1: V0 += 2, VF is left alone
+        let mut cpu = Chip8::new(parse_text("60FF\n7002".to_owned()));
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!(cpu.dump_registers()[0], 1);
+        assert_eq!(cpu.dump_registers()[0xF], 0);
+    }
+
+    #[test]
+    fn test_rand() {
+        // Shifts of 64 bits or more used to overflow in debug builds
+        let (mut a, mut b) = (Rand::new(u64::MAX), Rand::new(u64::MAX));
+        for _ in 0..1000 {
+            assert_eq!(a.rand(), b.rand());
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        // 0: skip if key V0 down, 1: V1 = 1, 2: skip if key V0 up, 3: V2 = 1, 4: wait for a key into V3
+        let program = parse_text("E09E\n6101\nE0A1\n6201\nF30A".to_owned());
+        let mut cpu = Chip8::new(program.clone());
+        cpu.set_keys(1);
+        for _ in 0..4 {
+            cpu.cycle();
+        }
+        assert_eq!((cpu.dump_registers()[1], cpu.dump_registers()[2], cpu.dump_pc()), (0, 1, 5));
+        let mut cpu = Chip8::new(program);
+        for _ in 0..4 {
+            cpu.cycle();
+        }
+        assert_eq!((cpu.dump_registers()[1], cpu.dump_registers()[2], cpu.dump_pc()), (1, 0, 4));
+        cpu.set_keys(1 << 7);
+        cpu.cycle();
+        assert_eq!((cpu.dump_registers()[3], cpu.dump_pc()), (7, 5));
+    }
+
+    ///Executes a debugger command and ticks until the session stops again
+    fn run(session: &mut Session, line: &str, symbols: Option<&SymbolTable>) -> Option<StopReason> {
+        if let Some(stop) = session.execute(&Command::parse(line, symbols).unwrap()) {
+            return Some(stop);
+        }
+        while session.is_running() {
+            if let Some(stop) = session.tick().stop {
+                return Some(stop);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_session() {
+        let symbols = SymbolTable::parse("label 0x003 loop").unwrap();
+        let mut session = Session::new(parse_text("6001\n6102\n6203\n7001\n1003".to_owned()));
+        let run = |session: &mut Session, line: &str| {
+            session.execute(&Command::parse(line, Some(&symbols)).unwrap());
+            loop {
+                if let Some(stop) = session.tick().stop {
+                    return stop;
+                }
+            }
+        };
+        assert_eq!(run(&mut session, "step 2"), StopReason::Stepped);
+        assert_eq!(session.cpu().dump_pc(), 2);
+        assert_eq!(run(&mut session, "run loop"), StopReason::Reached(3));
+        assert_eq!(run(&mut session, "until 0x3"), StopReason::Reached(3));
+        assert_eq!(session.cpu().dump_registers()[0], 2);
+        session.execute(&Command::Reset);
+        assert_eq!(session.cpu().dump_pc(), 0);
+        assert!(!session.is_running());
+        assert!(Command::parse("step x", None).is_err());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // V0 counts up forever, I points at the BCD digits of V0
+        let mut session = Session::new(parse_text("A300\n7001\nF033\nD011\n1001".to_owned()));
+        let run = |session: &mut Session, line: &str| {
+            session.execute(&Command::parse(line, None).unwrap());
+            if !session.is_running() {
+                return StopReason::Paused;
+            }
+            loop {
+                if let Some(stop) = session.tick().stop {
+                    return stop;
+                }
+            }
+        };
+        let expr = Expr::parse("V0 == 0x10 && [I+2] > 5 || !(PC - 1)").unwrap();
+        assert_eq!(expr.to_string(), "(((V0 == 0x10) && ([(I + 0x2)] > 0x5)) || !(PC - 0x1))");
+        assert!(Expr::parse("V0 ==").is_err() && Expr::parse("VG").is_err());
+
+        run(&mut session, "break op DRW");
+        run(&mut session, "tbreak 2 if V0 == 3");
+        assert_eq!(run(&mut session, "continue"), StopReason::Breakpoint(1));
+        assert_eq!(session.cpu().dump_pc(), 3);
+        assert_eq!(run(&mut session, "continue"), StopReason::Breakpoint(1));
+        assert_eq!(run(&mut session, "continue"), StopReason::Breakpoint(2));
+        assert_eq!(session.cpu().dump_registers()[0], 3);
+        assert_eq!(session.breakpoints().iter().count(), 1);
+        run(&mut session, "disable 1");
+        run(&mut session, "break type DISPLAY if [I+2] == 6 && V0 > 100");
+        assert_eq!(run(&mut session, "continue"), StopReason::Breakpoint(3));
+        assert_eq!(session.cpu().dump_registers()[0], 106);
+        assert_eq!(session.breakpoints().iter().next().unwrap().hits, 2);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // 0: I = 0x300, 1: V0 = 42, 2: BCD of V0, 3: V1 = 7, 4: store V0-V1, 5: draw from I
+        let mut session = Session::new(parse_text("A300\n602A\nF033\n6107\nF155\nD012".to_owned()));
+        run(&mut session, "watch 0x301..0x303", None);
+        run(&mut session, "watch V1", None);
+        let Some(StopReason::Watch(hit)) = run(&mut session, "continue", None) else { panic!("no watch hit") };
+        assert_eq!((hit.id, hit.pc, hit.op_code), (1, 2, 0xF033));
+        assert_eq!(hit.event, WatchEvent::Memory(MemAccess { addr: 0x301, kind: AccessKind::Write, old: 0, new: 4 }));
+        let Some(StopReason::Watch(hit)) = run(&mut session, "continue", None) else { panic!("no watch hit") };
+        assert_eq!(hit.event, WatchEvent::Register { reg: WatchReg::V(1), old: 0, new: 7 });
+        run(&mut session, "unwatch 1", None);
+        run(&mut session, "rwatch 0x301", None);
+        let Some(StopReason::Watch(hit)) = run(&mut session, "continue", None) else { panic!("no watch hit") };
+        assert_eq!((hit.id, hit.pc), (3, 5));
+        assert_eq!(hit.event, WatchEvent::Memory(MemAccess { addr: 0x301, kind: AccessKind::Read, old: 7, new: 7 }));
+        assert!(Command::parse("watch 0xFFFF", None).is_err());
+    }
+
+    #[test]
+    fn test_memory_view() {
+        assert_eq!(Command::parse("mem down 2", None), Ok(Command::Memory(MemoryCommand::Scroll(2))));
+        assert_eq!(Command::parse("mem 0x300", None), Ok(Command::Memory(MemoryCommand::Pin(0x300))));
+        let mut view = MemoryView::default();
+        assert_eq!(view.start(0x345), 0x320);
+        assert_eq!(view.start(0xFFF), 0xF00);
+        view.anchor = MemoryAnchor::Pinned(0x123);
+        assert_eq!(view.start(0), 0x120);
+        view.sprite = true;
+        assert_eq!(view.start(0), 0x123);
+    }
+
+    #[test]
+    fn test_code_window() {
+        assert_eq!(code_window_start(3, 100, CODE_ROWS), 0);
+        assert_eq!(code_window_start(40, 100, CODE_ROWS), 40 - CODE_ROWS / 2);
+        assert_eq!(code_window_start(99, 100, CODE_ROWS), 100 - CODE_ROWS);
+        assert_eq!(code_window_start(5, 10, CODE_ROWS), 0);
+        assert_eq!(code_window_start(40, 100, 10), 35);
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::compute(200, 80, &Panel::DEFAULT, 0);
+        assert!(Panel::DEFAULT.iter().all(|p| layout.rect(*p).is_some()));
+        assert_eq!(layout.rect(Panel::Profile), None);
+        assert_eq!(layout.tab_bar, None);
+        // Panels never overlap
+        for (i, (_, a)) in layout.panels().iter().enumerate() {
+            for (_, b) in &layout.panels()[i + 1..] {
+                assert!(a.x + a.width <= b.x || b.x + b.width <= a.x || a.y + a.height <= b.y || b.y + b.height <= a.y);
+            }
+        }
+
+        // Too narrow for the code panel, it shares the memory panel's space
+        let layout = Layout::compute(120, 60, &Panel::DEFAULT, 0);
+        assert_eq!(layout.tabs, vec![Panel::Code, Panel::Memory]);
+        assert_eq!(layout.active_tab(), Some(Panel::Code));
+        let bar = layout.tab_bar.unwrap();
+        assert_eq!(layout.rect(Panel::Code).unwrap().y, bar.y + 1);
+        assert_eq!(layout.rect(Panel::Memory), None);
+        let layout = Layout::compute(120, 60, &Panel::DEFAULT, 1);
+        assert_eq!(layout.active_tab(), Some(Panel::Memory));
+        assert_eq!(layout.rect(Panel::Code), None);
+
+        // Nothing below the screen fits in a short terminal
+        let layout = Layout::compute(80, 36, &Panel::DEFAULT, 0);
+        assert!(layout.rect(Panel::Breakpoints).is_none());
+        assert!(!layout.collapsed.is_empty());
+
+        // The history panel only just fits, so it can't give up a row for a tab bar
+        let layout = Layout::compute(200, 41, &[Panel::Memory, Panel::History], 0);
+        assert_eq!(layout.tab_bar, None);
+        assert_eq!(layout.rect(Panel::History).unwrap().height, 4);
+        assert_eq!(layout.collapsed, vec![Panel::Memory]);
+
+        let layout = Layout::compute(200, 80, &Panel::ALL, 0);
+        assert!(Panel::ALL.iter().all(|p| layout.rect(*p).is_some()));
+
+        // Compact mode lays out nothing
+        let layout = Layout::compute(200, 80, &[], 0);
+        assert!(layout.panels().is_empty());
+        assert_eq!(layout.tab_bar, None);
+    }
+
+    ///Sends a packet and returns the stub's reply
+    fn gdb_request(stream: &mut TcpStream, packet: &str) -> String {
+        write_packet(stream, packet).unwrap();
+        gdb_reply(stream)
+    }
+
+    ///Reads the next reply packet, acknowledging it like gdb would
+    fn gdb_reply(stream: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0u8];
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'$' {}
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'#' {
+            reply.push(byte[0]);
+        }
+        stream.read_exact(&mut [0u8; 2]).unwrap();
+        std::io::Write::write_all(stream, b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_gdb() {
+        // 0: V0 = 1, 1: V1 = 2, 2: V0 += 1, 3: jump 2
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut stub = GdbStub::new(Session::new(parse_text("6001\n6102\n7001\n1002".to_owned())));
+            stub.serve(&listener).unwrap();
+            stub.session().cpu().dump_registers()[1]
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert!(gdb_request(&mut client, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(gdb_request(&mut client, "qXfer:features:read:target.xml:0,800").starts_with('l'));
+        assert!(gdb_request(&mut client, "qXfer:features:read:target.xml:1,ffffffffffffffff").starts_with('l'));
+        assert_eq!(gdb_request(&mut client, "?"), "S05");
+        assert_eq!(gdb_request(&mut client, "Z0,3,2"), "OK");
+        assert_eq!(gdb_request(&mut client, "c"), "T05swbreak:;");
+        let registers = gdb_request(&mut client, "g");
+        assert_eq!(&registers[..4], "0202");
+        assert_eq!(&registers[32..40], "00000300");
+        assert_eq!(gdb_request(&mut client, "s"), "S05");
+        assert_eq!(gdb_request(&mut client, "p11"), "0200");
+        assert_eq!(gdb_request(&mut client, "M300,2:beef"), "OK");
+        assert_eq!(gdb_request(&mut client, "m2ff,3"), "00beef");
+        assert_eq!(gdb_request(&mut client, "m1000,1"), "E01");
+        assert_eq!(gdb_request(&mut client, "mffffffffffffffff,2"), "E01");
+        assert_eq!(gdb_request(&mut client, "Mffffffffffffffff,1:00"), "E01");
+        // A corrupted packet is nacked and the retry answered
+        std::io::Write::write_all(&mut client, b"$?#00").unwrap();
+        assert_eq!(gdb_request(&mut client, "?"), "S05");
+        assert_eq!(gdb_request(&mut client, "P1=07"), "OK");
+        assert_eq!(gdb_request(&mut client, "z0,3,2"), "OK");
+        // Free running until interrupted
+        write_packet(&mut client, "c").unwrap();
+        std::io::Write::write_all(&mut client, &[0x03]).unwrap();
+        assert_eq!(gdb_reply(&mut client), "S02");
+        assert_eq!(gdb_request(&mut client, "?"), "S05");
+        assert_eq!(gdb_request(&mut client, "D"), "OK");
+        assert_eq!(server.join().unwrap(), 7);
+    }
+
+    ///Sends a request and returns the response, skipping nothing so event order is checked
+    fn dap_request(to: &mut impl std::io::Write, from: &mut impl std::io::BufRead, command: &str, arguments: Json) -> Json {
+        let request = Json::object([("seq", 1u64.into()), ("type", "request".into()), ("command", command.into()), ("arguments", arguments)]);
+        write_message(to, &request).unwrap();
+        let response = read_message(from).unwrap().unwrap();
+        assert_eq!((response.get("type").as_str(), response.get("command").as_str()), (Some("response"), Some(command)));
+        response
+    }
+
+    fn dap_event(from: &mut impl std::io::BufRead, event: &str) -> Json {
+        let message = read_message(from).unwrap().unwrap();
+        assert_eq!(message.get("event").as_str(), Some(event), "{}", message);
+        message.get("body").clone()
+    }
+
+    #[test]
+    fn test_dap() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":"q\"\u00e9\n"}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("b").as_str(), Some("q\"é\n"));
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+        assert!(Json::parse("[1,]").is_err() && Json::parse("{} x").is_err());
+
+        // 0: V0 = 1, 1: V0 += 1, 2: call 5, 3: jump 1, 4: clear, 5: V1 += 1, 6: ret
+        let path = std::env::temp_dir().join(format!("skye-dap-{}.hex", std::process::id()));
+        std::fs::write(&path, "6001\n7001\n2005\n1001\n00E0\n7101\n00EE\n").unwrap();
+        let program = path.to_str().unwrap();
+        let (input, mut to) = std::io::pipe().unwrap();
+        let (output, writer) = std::io::pipe().unwrap();
+        let server = std::thread::spawn(move || DapServer::new(writer).serve(input));
+        let mut from = std::io::BufReader::new(output);
+        let (to, from) = (&mut to, &mut from);
+
+        let response = dap_request(to, from, "initialize", Json::object([("adapterID", "skye".into())]));
+        assert_eq!(response.get("body").get("supportsConfigurationDoneRequest"), &Json::Bool(true));
+        assert_eq!(dap_request(to, from, "launch", Json::object([("program", "/no/such.hex".into())])).get("success"), &Json::Bool(false));
+        // A listing that doesn't parse fails the launch rather than the adapter
+        let bad_path = std::env::temp_dir().join(format!("skye-dap-bad-{}.hex", std::process::id()));
+        std::fs::write(&bad_path, "6001\nzz\n").unwrap();
+        let response = dap_request(to, from, "launch", Json::object([("program", bad_path.to_str().unwrap().into())]));
+        std::fs::remove_file(&bad_path).unwrap();
+        assert_eq!(response.get("success"), &Json::Bool(false));
+        assert!(response.get("message").as_str().unwrap().contains("line 2"));
+        let launch = Json::object([("program", program.into()), ("stopOnEntry", true.into())]);
+        assert_eq!(dap_request(to, from, "launch", launch).get("success"), &Json::Bool(true));
+        dap_event(from, "initialized");
+        let breakpoints = |lines: &[u64]| {
+            let lines: Vec<Json> = lines.iter().map(|l| Json::object([("line", (*l).into())])).collect();
+            Json::object([("source", Json::object([("path", program.into())])), ("breakpoints", lines.into())])
+        };
+        let response = dap_request(to, from, "setBreakpoints", breakpoints(&[6, 9]));
+        let set = response.get("body").get("breakpoints").as_array();
+        assert_eq!((set[0].get("verified"), set[0].get("line").as_u64()), (&Json::Bool(true), Some(6)));
+        assert_eq!(set[1].get("verified"), &Json::Bool(false));
+        dap_request(to, from, "configurationDone", Json::Null);
+        assert_eq!(dap_event(from, "stopped").get("reason").as_str(), Some("entry"));
+
+        dap_request(to, from, "continue", Json::object([("threadId", 1u64.into())]));
+        let stopped = dap_event(from, "stopped");
+        assert_eq!(stopped.get("reason").as_str(), Some("breakpoint"));
+        assert_eq!(stopped.get("hitBreakpointIds").as_array(), [Json::from(1u64)]);
+        let trace = dap_request(to, from, "stackTrace", Json::object([("threadId", 1u64.into())]));
+        let frames = trace.get("body").get("stackFrames").as_array();
+        let lines: Vec<Option<u64>> = frames.iter().map(|f| f.get("line").as_u64()).collect();
+        assert_eq!(lines, vec![Some(6), Some(3)]);
+        assert_eq!(frames[0].get("source").get("path").as_str(), Some(program));
+        let variables = dap_request(to, from, "variables", Json::object([("variablesReference", 1u64.into())]));
+        let v0 = &variables.get("body").get("variables").as_array()[0];
+        assert_eq!((v0.get("name").as_str(), v0.get("value").as_str()), (Some("V0"), Some("0x02")));
+
+        dap_request(to, from, "next", Json::Null);
+        assert_eq!(dap_event(from, "stopped").get("reason").as_str(), Some("step"));
+        dap_request(to, from, "stepOut", Json::Null);
+        dap_event(from, "stopped");
+        let trace = dap_request(to, from, "stackTrace", Json::Null);
+        assert_eq!(trace.get("body").get("totalFrames").as_u64(), Some(1));
+        assert_eq!(trace.get("body").get("stackFrames").as_array()[0].get("line").as_u64(), Some(4));
+        let result = dap_request(to, from, "evaluate", Json::object([("expression", "V1 + 1".into())]));
+        assert_eq!(result.get("body").get("result").as_str(), Some("0x2 (2)"));
+        let screen = dap_request(to, from, "framebuffer", Json::Null);
+        assert_eq!(screen.get("body").get("rows").as_array().len(), 32);
+
+        // Free running until paused
+        dap_request(to, from, "setBreakpoints", breakpoints(&[]));
+        dap_request(to, from, "continue", Json::Null);
+        dap_request(to, from, "pause", Json::Null);
+        assert_eq!(dap_event(from, "stopped").get("reason").as_str(), Some("pause"));
+        dap_request(to, from, "disconnect", Json::Null);
+        server.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trace() {
+        // 0: V0 = 1, 1: I = 0x300, 2: V0 += 1, 3: jump 2
+        let program = parse_text("6001\nA300\n7001\n1002".to_owned());
+        let path = std::env::temp_dir().join(format!("skye-trace-{}.trace", std::process::id()));
+        let trace = |filter: TraceFilter| {
+            let mut session = Session::new(program.clone());
+            session.set_tracer(Some(Tracer::create(&path, filter).unwrap()));
+            run(&mut session, "step 6", None);
+            session.set_tracer(None).unwrap().finish().unwrap();
+            std::fs::read_to_string(&path).unwrap()
+        };
+        let full = trace(TraceFilter::default());
+        let lines: Vec<&str> = full.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "       1 000 6001 LD V0, 0x01     V0=01");
+        assert_eq!(lines[1], "       2 001 A300 LD I, 0x300     I=0300");
+        assert_eq!(lines[3], "       4 003 1002 JP 0x002");
+        let filter = TraceFilter { types: vec![OpCodeType::FLOW(0)], ..Default::default() };
+        assert_eq!(trace(filter).lines().count(), 2);
+        let filter = TraceFilter { ranges: vec![TraceFilter::parse_range("0+2", None).unwrap()], ..Default::default() };
+        assert_eq!(trace(filter).lines().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first_divergence(&full, &full), None);
+        let changed = full.replacen("V0=03", "V0=04", 1);
+        let divergence = first_divergence(&full, &changed).unwrap();
+        assert_eq!((divergence.line, divergence.field()), (5, "registers"));
+        let divergence = first_divergence(&full, &lines[..4].join("\n")).unwrap();
+        assert_eq!((divergence.line, divergence.field(), divergence.right), (5, "length", None));
+    }
+
+    #[test]
+    fn test_call_stepping() {
+        // 0: call 3, 1: V1 = 1, 2: jump 2, 3: call 6, 4: V2 = 2, 5: ret, 6: V3 = 3, 7: ret
+        let mut session = Session::new(parse_text("2003\n6101\n1002\n2006\n6202\n00EE\n6303\n00EE".to_owned()));
+        assert_eq!(run(&mut session, "next", None), Some(StopReason::Stepped));
+        assert_eq!(session.cpu().dump_pc(), 1);
+        assert_eq!(session.cpu().dump_registers()[1..4], [0, 2, 3]);
+
+        session.execute(&Command::Reset);
+        run(&mut session, "step 2", None);
+        assert_eq!(session.cpu().dump_pc(), 6);
+        assert_eq!(session.backtrace(), vec![Frame { call_site: 3, target: 6 }, Frame { call_site: 0, target: 3 }]);
+        assert_eq!(run(&mut session, "finish", None), Some(StopReason::Stepped));
+        assert_eq!(session.cpu().dump_pc(), 4);
+        assert_eq!(session.backtrace().len(), 1);
+        run(&mut session, "break 1", None);
+        run(&mut session, "next", None);
+        assert_eq!(run(&mut session, "finish", None), Some(StopReason::Stepped));
+        assert_eq!((session.cpu().dump_pc(), session.backtrace().len()), (1, 0));
+        // Nothing to finish at the outermost level
+        assert_eq!(run(&mut session, "finish", None), None);
+        assert_eq!(session.cpu().dump_pc(), 1);
+    }
+
+    #[test]
+    fn test_profile() {
+        // 0: call 3, 1: V1 = 1, 2: jump 2, 3: call 6, 4: V2 = 2, 5: ret, 6: V3 = 3, 7: ret
+        let mut session = Session::new(parse_text("2003\n6101\n1002\n2006\n6202\n00EE\n6303\n00EE".to_owned()));
+        session.set_profiler(Some(Profiler::new()));
+        run(&mut session, "step 3", None);
+        // Calls still open are costed up to now
+        let open = session.profiler().unwrap().subroutines();
+        assert_eq!(open[0], (3, SubroutineCost { calls: 1, inclusive: 2, exclusive: 1 }));
+
+        run(&mut session, "step 7", None);
+        let profiler = session.profiler().unwrap();
+        assert_eq!(profiler.executed(), 10);
+        assert_eq!(profiler.hot_spots()[0], (2, 3));
+        assert_eq!(profiler.count(6), 1);
+        assert_eq!(
+            profiler.subroutines(),
+            vec![(3, SubroutineCost { calls: 1, inclusive: 5, exclusive: 3 }), (6, SubroutineCost { calls: 1, inclusive: 2, exclusive: 2 })]
+        );
+        assert_eq!(profiler.mix(), vec![("FLOW", 7), ("CONST", 3)]);
+
+        let mut symbols = SymbolTable::new();
+        symbols.add_label(3, "update");
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, Some(&symbols)).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Profile of 10 instructions"));
+        assert!(report.contains("       1          5          3  50.00  update"));
+    }
+
+    #[test]
+    fn test_coverage() {
+        // 0: V0 = 1, 1: skip if V0 == 1, 2: V1 = 5, 3: skip if V0 == 2, 4: V2 = 5, 5: jump 5
+        let text = "6001\n3001\n6105\n3002\n6205\n1005\n";
+        let symbols = SymbolTable::from_listing("game.hex", text);
+        let mut session = Session::new(parse_text(text.to_owned()));
+        session.set_coverage(Some(Coverage::new()));
+        run(&mut session, "step 6", None);
+        let coverage = session.coverage().unwrap();
+        let program = session.cpu().dump_program();
+        assert_eq!((coverage.count(2), coverage.count(5)), (0, 2));
+        assert_eq!(coverage.skip(1), SkipCount { taken: 1, not_taken: 0 });
+        assert_eq!(coverage.skip(3), SkipCount { taken: 0, not_taken: 1 });
+        let summary = coverage.summary(program, Some(&symbols));
+        assert_eq!(summary, CoverageSummary { instructions: 6, executed: 5, branches: 4, branches_hit: 2 });
+        assert_eq!(summary.to_string(), "5/6 instructions (83.3%), 2/4 skip branches (50.0%)");
+
+        let listing = coverage.annotate_listing(program, None);
+        assert!(listing.contains("\n    #####: 0x002: 6105"));
+        assert!(listing.contains("        1: 0x001: 3001  SE V0, 0x01  [skip taken 1, not taken 0]\n"));
+        let source = coverage.annotate_source("game.hex", text, program, &symbols);
+        assert_eq!(source.lines().nth(3), Some("        1:    4: 3002  [skip taken 0, not taken 1]"));
+        let lcov = coverage.to_lcov(program, &symbols).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:game.hex\nBRDA:2,1,0,0\nBRDA:2,1,1,1\nBRDA:4,3,0,1\nBRDA:4,3,1,0\nBRF:4\nBRH:2\nDA:1,1\n"));
+        assert!(lcov.ends_with("DA:6,2\nLF:6\nLH:5\nend_of_record\n"));
+        assert_eq!(coverage.to_lcov(program, &SymbolTable::new()), None);
+    }
+
+    #[test]
+    fn test_sprites() {
+        // 0: V0 = 5, 1: V1 = 3, 2: I = 0x10, 3: draw 5 rows at V0,V1, 4: V0 += 1, 5: draw 16x16, 6: jump 6
+        let mut session = Session::new(parse_text("6005\n6103\nA010\nD015\n7001\nD010\n1006".to_owned()));
+        run(&mut session, "step 4", None);
+        let first = Draw { pc: 3, addr: 0x10, x: 5, y: 3, height: 5 };
+        assert_eq!(session.last_draw(), Some(first));
+        run(&mut session, "step 2", None);
+        assert_eq!(session.last_draw(), Some(Draw { pc: 5, x: 6, height: 0, ..first }));
+        // Rewinding finds the draw before the new position again
+        run(&mut session, "back 2", None);
+        assert_eq!(session.last_draw(), Some(first));
+        run(&mut session, "back 2", None);
+        assert_eq!(session.last_draw(), None);
+
+        assert_eq!(Command::parse("sprites height 8", None), Ok(Command::Sprites(SpriteCommand::Height(8))));
+        assert_eq!(Command::parse("sprites up 2", None), Ok(Command::Sprites(SpriteCommand::Scroll(-2))));
+        assert!(Command::parse("sprites height 16", None).is_err());
+        let mut debugger = Debugger::new((200, 80));
+        assert_eq!(debugger.layout().rect(Panel::Sprites), None);
+        debugger.apply_sprite_command(SpriteCommand::Follow, Some(first));
+        assert!(debugger.layout().rect(Panel::Sprites).is_some());
+        let view = *debugger.sprite_view();
+        assert_eq!((view.size(Some(first)), view.start(Some(first))), ((8, 5), 0x10));
+        debugger.apply_sprite_command(SpriteCommand::Wide(true), Some(first));
+        debugger.apply_sprite_command(SpriteCommand::Scroll(1), Some(first));
+        let view = *debugger.sprite_view();
+        assert_eq!((view.size(Some(first)), view.start(Some(first))), ((16, 16), 0x30));
+        debugger.apply_sprite_command(SpriteCommand::Hide, None);
+        assert_eq!(debugger.layout().rect(Panel::Sprites), None);
+    }
 
+    #[test]
+    fn test_display_backends() {
+        // 0: V0 = 0xF0, 1: I = 0x300, 2: store V0, 3: I = 0x300, 4: draw 1 row at V1,V1, 5: jump 5
+        let mut session = Session::new(parse_text("60F0\nA300\nF055\nA300\nD111\n1005".to_owned()));
+        let mut recording = RecordingDisplay::default();
+        recording.set_mode(LORES);
+        recording.draw(session.cpu().dump_vram());
+        run(&mut session, "step 5", None);
+        recording.draw(session.cpu().dump_vram());
+        assert_eq!(recording.events[0], DisplayEvent::Mode(LORES));
+        let frames: Vec<&[u8]> = recording.frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].iter().all(|p| *p == 0));
+        assert_eq!(&frames[1][..5], &[1, 1, 1, 1, 0]);
+
+        let tiny = Mode { width: 3, height: 2 };
+        assert_eq!(encode_pbm(tiny, &[1, 0, 1, 0, 1, 0]), b"P1\n3 2\n1 0 1\n0 1 0\n");
+        let pbm = String::from_utf8(encode_pbm(LORES, &[1; 2048])).unwrap();
+        assert!(pbm.lines().all(|line| line.len() <= 70));
+        assert_eq!(pbm.lines().count(), 2 + 32 * 2);
+        assert_eq!(pbm.lines().nth(3).unwrap().len(), 29 * 2 - 1);
+        assert_eq!(ImageFormat::Ppm.encode(tiny, &[1, 0, 1, 0, 1, 0], &Theme::default())[11..14], [255, 255, 255]);
+
+        let dir = std::env::temp_dir().join(format!("skye-frames-{}", std::process::id()));
+        let mut images = ImageDisplay::create(&dir, ImageFormat::Pbm).unwrap();
+        images.set_mode(tiny);
+        images.draw(&[0; 6]);
+        images.redraw(&[1; 6]);
+        images.draw(&[1; 6]);
+        assert_eq!(images.frames(), 2);
+        assert!(images.finish().is_ok());
+        assert_eq!(std::fs::read(dir.join("frame000001.pbm")).unwrap(), b"P1\n3 2\n1 1 1\n1 1 1\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Column 0 fully lit, the top left 2x2 square and the bottom right pixel
+        let square = Mode { width: 3, height: 4 };
+        let vram = [1, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1];
+        assert_eq!(TextStyle::Block.render(square, &vram), ["██ ", "██ ", "█  ", "█ █"]);
+        assert_eq!(TextStyle::Ascii.render(square, &vram)[3], "# #");
+        assert_eq!(TextStyle::HalfBlock.render(square, &vram), ["██ ", "█ ▄"]);
+        assert_eq!(TextStyle::Braille.render(square, &vram), ["\u{285F}\u{2840}"]);
+        assert_eq!(TextStyle::Braille.footprint(LORES), (32, 8));
+        assert_eq!(TextStyle::Braille.render(LORES, &[0; 2048])[0], " ".repeat(32));
+
+        assert!(backend_from_name("null").is_ok());
+        assert!(backend_from_name("ansi:braille").is_ok());
+        assert!(backend_from_name("ansi:sixel").is_err());
+        assert!(backend_from_name("pbm").is_err());
+        assert!(backend_from_name("vga").is_err());
+    }
+
+    #[test]
+    fn test_screen_diff() {
+        let mut screen = Screen::new((20, 4));
+        screen.print(format_args!("\x1B[2;3HPC = {:#05X}\x1B[K", 0x200));
+        assert_eq!(screen.diff(), "\x1B[2;3H\x1B[0mPC = 0x200");
+        // Same text again changes nothing, one digit only writes that digit
+        screen.print(format_args!("\x1B[2;3HPC = {:#05X}\x1B[K", 0x200));
+        assert_eq!(screen.diff(), "");
+        screen.print(format_args!("\x1B[2;3HPC = {:#05X}\x1B[K", 0x202));
+        assert_eq!(screen.diff(), "\x1B[2;12H\x1B[0m2");
+        // Styles split across writes, clearing the line end blanks what was there
+        screen.print(format_args!("\x1B[2;3H{}PC\x1B[0m\x1B[K", "\x1B[7m"));
+        assert_eq!(screen.row(2), "  PC                ");
+        assert_eq!(screen.diff(), "\x1B[2;3H\x1B[0m\x1B[7mPC\x1B[0m        \x1B[2;5H");
+        screen.invalidate_row(2);
+        assert_eq!(screen.diff(), "\x1B[2;1H\x1B[0m  \x1B[0m\x1B[7mPC\x1B[0m                \x1B[2;5H");
+
+        let mut display = Display::new(TextStyle::HalfBlock);
+        let mut vram = [0; 2048];
+        assert_eq!(display.frame_diff(&vram), "");
+        vram[64 * 3 + 10] = 1;
+        assert_eq!(display.frame_diff(&vram), "\x1B7\x1B[2;11H\x1B[0m▄\x1B[16;65H\x1B8");
+        assert_eq!(display.frame_diff(&vram), "");
+    }
+
+    #[test]
+    fn test_theme() {
+        assert_eq!(Rgb::parse("#33ff33"), Some(Rgb(0x33, 0xFF, 0x33)));
+        assert_eq!(Rgb::parse("#33ff3"), None);
+        assert_eq!(Rgb(255, 0, 0).to_ansi256(), 196);
+        assert_eq!(Rgb(0x80, 0x80, 0x80).to_ansi256(), 244);
+        for name in Theme::BUILT_IN {
+            assert_eq!(Theme::load(name).unwrap().name, name);
+        }
+        assert!(Theme::load("sepia").is_err());
+
+        let text = "# Amber with a blue second plane\ntheme = amber\ncolor2 = #0000ff\ncolors = truecolor\n";
+        let theme = Theme::parse(text).unwrap();
+        assert_eq!(theme.planes[0], Theme::built_in("amber").unwrap().background());
+        assert_eq!((theme.color(2), theme.color(3)), (Rgb(0, 0, 0xFF), Rgb(0xFF, 0xE0, 0xA0)));
+        assert_eq!(theme.depth, ColorDepth::TrueColor);
+        assert_eq!(theme.escape(theme.color(2), true), "\x1B[48;2;0;0;255m");
+        assert_eq!(Theme::parse("color4 = #000000"), Err("line 1: unknown key `color4`".to_owned()));
+        assert!(Theme::parse("background = black").is_err());
+
+        // Plane 1 over plane 2 in one half-block cell, then two background cells
+        let theme = Theme { depth: ColorDepth::Ansi256, ..Theme::built_in("contrast").unwrap() };
+        let lines = TextStyle::HalfBlock.render_colored(Mode { width: 3, height: 2 }, &[1, 0, 0, 2, 0, 0], &theme);
+        assert_eq!(lines, ["\x1B[0m\x1B[38;5;231m\x1B[48;5;226m▀\x1B[0m\x1B[38;5;231m\x1B[48;5;16m  \x1B[0m"]);
+        let mut display = Display::new(TextStyle::Block);
+        display.set_theme(&theme);
+        assert_eq!(display.theme(), Some(&theme));
+        let mut recording = RecordingDisplay::default();
+        recording.set_theme(&theme);
+        assert_eq!(recording.events, [DisplayEvent::Theme(theme)]);
+    }
+
+    #[test]
+    fn test_screenshot() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(zlib_stored(b""), [0x78, 0x01, 1, 0, 0, 0xFF, 0xFF, 0, 0, 0, 1]);
+        // Blocks hold at most 65535 bytes, only the last one is final
+        let data = vec![7; 70_000];
+        let stream = zlib_stored(&data);
+        assert_eq!(&stream[2..7], &[0, 0xFF, 0xFF, 0, 0]);
+        assert_eq!(&stream[65_542..65_547], &[1, 0x71, 0x11, 0x8E, 0xEE]);
+        assert_eq!(stream.len(), 2 + 5 + 65_535 + 5 + 4_465 + 4);
+
+        let palette = [Rgb(0, 0, 0), Rgb(0xFF, 0xB0, 0)];
+        let png = encode_indexed(2, 1, &palette, &[1, 0]);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        let ihdr = &png[8..33];
+        assert_eq!(&ihdr[..8], b"\0\0\0\x0DIHDR");
+        assert_eq!(&ihdr[8..21], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+        assert_eq!(ihdr[21..], crc32(&ihdr[4..21]).to_be_bytes());
+        assert_eq!(&png[33..47], b"\0\0\0\x06PLTE\0\0\0\xFF\xB0\0");
+        let idat = zlib_stored(&[0, 1, 0]);
+        assert_eq!(&png[55..59], b"IDAT");
+        assert_eq!(&png[59..59 + idat.len()], &idat[..]);
+        assert!(png.ends_with(b"\0\0\0\0IEND\xAE\x42\x60\x82"));
+
+        let (mode, scaled) = scale_frame(Mode { width: 2, height: 1 }, &[1, 0], 2).unwrap();
+        assert_eq!(scale_frame(LORES, &[0; 2048], usize::MAX), None);
+        assert_eq!((mode, scaled), (Mode { width: 4, height: 2 }, vec![1, 1, 0, 0, 1, 1, 0, 0]));
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("skye-shot-{}.pbm", std::process::id()));
+        save_screenshot(&path, Mode { width: 2, height: 1 }, &[1, 0], &Theme::default(), 2).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"P1\n4 2\n1 1 0 0\n1 1 0 0\n");
+        std::fs::remove_file(&path).unwrap();
+        let path = dir.join(format!("skye-shot-{}.png", std::process::id()));
+        save_screenshot(&path, LORES, &[0; 2048], &Theme::built_in("amber").unwrap(), 3).unwrap();
+        let png = std::fs::read(&path).unwrap();
+        assert_eq!(&png[16..24], &[0, 0, 0, 192, 0, 0, 0, 96]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(save_screenshot(dir.join("shot.gif"), LORES, &[0; 2048], &Theme::default(), 1).is_err());
+
+        assert_eq!(Command::parse("ss", None), Ok(Command::Screenshot(None, 4)));
+        assert_eq!(Command::parse("screenshot bug.png 8", None), Ok(Command::Screenshot(Some("bug.png".to_owned()), 8)));
+        assert_eq!(Command::parse("screenshot 2", None), Ok(Command::Screenshot(None, 2)));
+        assert!(Command::parse("screenshot 0", None).is_err());
+        assert!(Command::parse("screenshot 65", None).is_err());
+        assert!(Command::parse("ss 18446744073709551615", None).is_err());
+        assert!(save_screenshot(&path, LORES, &[0; 2048], &Theme::default(), 1_000_000).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        // 0: V0 = random, 1: V1 += 1, 2: skip if key V2 is down, 3: jump 0, 4: V3 = 1, 5: jump 0
+        let program = parse_text("C0FF\n7101\nE29E\n1000\n6301\n1000".to_owned());
+        let mut session = Session::with_seed(program, 7);
+        run(&mut session, "step 1000", None);
+        run(&mut session, "keys 0", None);
+        run(&mut session, "step 500", None);
+        let middle = (session.cpu().dump_registers(), session.cpu().dump_pc());
+        run(&mut session, "keys", None);
+        run(&mut session, "step 1000", None);
+        let end = (session.cpu().dump_registers(), session.cpu().dump_pc());
+        assert_eq!(session.cycles(), 2500);
+        assert_eq!(Command::parse("keys 1 f A", None), Ok(Command::Keys(0x8402)));
+        assert!(Command::parse("keys 10", None).is_err());
+
+        // Replays reproduce the random numbers and key presses
+        assert_eq!(run(&mut session, "seek 1500", None), Some(StopReason::Stepped));
+        assert_eq!((session.cpu().dump_registers(), session.cpu().dump_pc()), middle);
+        run(&mut session, "step 1000", None);
+        assert_eq!((session.cpu().dump_registers(), session.cpu().dump_pc()), end);
+        assert_eq!(run(&mut session, "back 3", None), Some(StopReason::Stepped));
+        assert_eq!(session.cycles(), 2497);
+
+        run(&mut session, "break 4", None);
+        assert_eq!(run(&mut session, "reverse-continue", None), Some(StopReason::Breakpoint(1)));
+        assert_eq!(session.cpu().dump_pc(), 4);
+        assert!(session.cycles() < 1500);
+        let changed = StopReason::Changed { reg: WatchReg::V(3), old: 0, new: 1 };
+        assert_eq!(run(&mut session, "reverse-until V3", None), Some(changed));
+        assert_eq!((session.cpu().dump_pc(), session.cpu().dump_registers()[3]), (4, 0));
+        assert_eq!(session.history().timeline().nth(session.cycles() as usize).unwrap().pc, 4);
+        assert_eq!(run(&mut session, "back 5000", None), Some(StopReason::HistoryStart));
+        assert_eq!(session.cycles(), 0);
+    }
+
+    #[test]
+    fn test_script() {
+        // 0: I = 0x300, 1: V0 = 42, 2: BCD of V0, 3: draw 3 rows, 4: jump 4
+        let symbols = SymbolTable::parse("label 0x004 done").unwrap();
+        let session = Session::new(parse_text("A300\n602A\nF033\nD013\n1004".to_owned()));
+        let script = "# BCD check\nbreak done\ncontinue\nprint [I+1] * 10 + [I+2]\nassert V0 == 42\nx 0x300 3\nassert V0 == 1\nbogus\ndelete 1\nlimit 50\nc\nquit\nstep\n";
+        let mut runner = ScriptRunner::new(session, Some(symbols), Vec::new());
+        assert_eq!(runner.run(script.as_bytes(), false).unwrap(), 2);
+        assert_eq!(runner.session().cpu().dump_pc(), 4);
+        runner.run_line("x 0xFFF 18446744073709551615").unwrap();
+        runner.run_line("screen").unwrap();
+        let output = String::from_utf8(runner.into_session_and_output().1).unwrap();
+        let expected = "(skye) break done\n\
+                        (skye) continue\n\
+                        hit breakpoint #1 at done\n\
+                        (skye) print [I+1] * 10 + [I+2]\n\
+                        (([(I + 0x1)] * 0xA) + [(I + 0x2)]) = 0x2A (42)\n\
+                        (skye) assert V0 == 42\n\
+                        (skye) x 0x300 3\n\
+                        0x300: 00 04 02\n\
+                        (skye) assert V0 == 1\n\
+                        line 7: assertion failed: (V0 == 0x1) at done\n\
+                        (skye) bogus\n\
+                        line 8: unknown command `bogus`\n\
+                        (skye) delete 1\n\
+                        (skye) limit 50\n\
+                        (skye) c\n\
+                        stopped after 50 instructions at done\n\
+                        (skye) quit\n";
+        assert!(output.starts_with(expected), "{}", output);
+        // The BCD digits 0, 4, 2 drawn as sprite rows at x = 42
+        let rest = &output[expected.len()..];
+        assert!(rest.starts_with("0xFFF: 00\n"));
+        let screen: Vec<&str> = rest.lines().skip(1).collect();
+        assert_eq!(screen.len(), 32);
+        assert_eq!(screen[1], format!("{}#{}", ".".repeat(47), ".".repeat(16)));
+        assert_eq!(screen[2].find('#'), Some(48));
+
+        // Screenshots from scripts use the theme they were given
+        let mut runner = ScriptRunner::new(Session::new(parse_text("00E0".to_owned())), None, Vec::new());
+        runner.set_theme(Theme::built_in("amber").unwrap());
+        let path = std::env::temp_dir().join(format!("skye-script-{}.ppm", std::process::id()));
+        runner.run_line(&format!("screenshot {} 1", path.display())).unwrap();
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image[13..16], [0x1A, 0x0F, 0x00]);
+    }
 }
@@ -0,0 +1,168 @@
+use std::{fs, path::Path};
+
+///24-bit color
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    ///Parses `#rrggbb`, the `#` is optional
+    pub fn parse(text: &str) -> Option<Rgb> {
+        let hex = text.strip_prefix('#').unwrap_or(text);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some(Rgb(channel(0)?, channel(2)?, channel(4)?))
+    }
+
+    ///Closest entry of the xterm 256-color palette, from the 6x6x6 cube or the gray ramp
+    pub fn to_ansi256(self) -> u8 {
+        const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+        let nearest = |c: u8| (0..6).min_by_key(|i| (LEVELS[*i] as i32 - c as i32).abs()).unwrap_or(0);
+        let (r, g, b) = (nearest(self.0), nearest(self.1), nearest(self.2));
+        let cube = Rgb(LEVELS[r], LEVELS[g], LEVELS[b]);
+        let average = (self.0 as u32 + self.1 as u32 + self.2 as u32) / 3;
+        let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+        let level = 8 + gray_index * 10;
+        let gray = Rgb(level, level, level);
+        if self.distance(gray) < self.distance(cube) {
+            232 + gray_index
+        } else {
+            16 + 36 * r as u8 + 6 * g as u8 + b as u8
+        }
+    }
+
+    fn distance(self, other: Rgb) -> u32 {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+        d(self.0, other.0) + d(self.1, other.1) + d(self.2, other.2)
+    }
+}
+
+///How colors are sent to the terminal
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ColorDepth {
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "256" => Some(ColorDepth::Ansi256),
+            "truecolor" | "24bit" => Some(ColorDepth::TrueColor),
+            _ => None,
+        }
+    }
+
+    ///Truecolor when `COLORTERM` says the terminal has it, 256 colors otherwise
+    pub fn detect() -> Self {
+        match std::env::var("COLORTERM").as_deref() {
+            Ok("truecolor" | "24bit") => ColorDepth::TrueColor,
+            _ => ColorDepth::Ansi256,
+        }
+    }
+}
+
+///Colors the display is drawn in. A pixel is the index of its color: 0 is the background,
+///1 the first bitplane, which is all plain CHIP-8 has, 2 the second and 3 both, as on XO-CHIP
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Theme {
+    pub name: String,
+    pub planes: [Rgb; 4],
+    pub depth: ColorDepth,
+}
+
+impl Default for Theme {
+    ///White on black
+    fn default() -> Self {
+        Theme::new("mono", [Rgb(0, 0, 0), Rgb(255, 255, 255), Rgb(170, 170, 170), Rgb(85, 85, 85)])
+    }
+}
+
+impl Theme {
+    ///Names of the built-in themes
+    pub const BUILT_IN: [&'static str; 4] = ["phosphor", "amber", "lcd", "contrast"];
+
+    pub fn new(name: &str, planes: [Rgb; 4]) -> Self {
+        Self { name: name.to_owned(), planes, depth: ColorDepth::detect() }
+    }
+
+    pub fn built_in(name: &str) -> Option<Theme> {
+        let planes = match name {
+            // Green phosphor tube
+            "phosphor" => [Rgb(0x00, 0x14, 0x00), Rgb(0x33, 0xFF, 0x33), Rgb(0x1A, 0x8C, 0x1A), Rgb(0xB0, 0xFF, 0xB0)],
+            "amber" => [Rgb(0x1A, 0x0F, 0x00), Rgb(0xFF, 0xB0, 0x00), Rgb(0xB3, 0x6B, 0x00), Rgb(0xFF, 0xE0, 0xA0)],
+            // Dark pixels on a greenish grey liquid crystal
+            "lcd" => [Rgb(0xC4, 0xCF, 0xA1), Rgb(0x2B, 0x33, 0x26), Rgb(0x6B, 0x7A, 0x4F), Rgb(0x4A, 0x55, 0x38)],
+            "contrast" => [Rgb(0x00, 0x00, 0x00), Rgb(0xFF, 0xFF, 0xFF), Rgb(0xFF, 0xFF, 0x00), Rgb(0x00, 0xFF, 0xFF)],
+            "mono" => return Some(Theme::default()),
+            _ => return None,
+        };
+        Some(Theme::new(name, planes))
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.planes[0]
+    }
+
+    ///Color of a pixel, by the bitplanes it is lit in
+    pub fn color(&self, pixel: u8) -> Rgb {
+        self.planes[pixel as usize & 3]
+    }
+
+    ///SGR escape setting the foreground, or the background when `background` is set
+    pub fn escape(&self, color: Rgb, background: bool) -> String {
+        let layer = if background { 48 } else { 38 };
+        match self.depth {
+            ColorDepth::TrueColor => format!("\x1B[{};2;{};{};{}m", layer, color.0, color.1, color.2),
+            ColorDepth::Ansi256 => format!("\x1B[{};5;{}m", layer, color.to_ansi256()),
+        }
+    }
+
+    ///Reads `key = value` lines, lines starting with `#` are comments. `theme` starts from a
+    ///built-in theme, `background`, `foreground` or `color0` to `color3` set the colors as
+    ///`#rrggbb` and `colors` is `256` or `truecolor`
+    pub fn parse(text: &str) -> Result<Theme, String> {
+        let mut theme = Theme::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |what: &str| format!("line {}: {}", number + 1, what);
+            let (key, value) = line.split_once('=').ok_or_else(|| error("expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+            let color = || Rgb::parse(value).ok_or_else(|| error(&format!("bad color `{}`, use #rrggbb", value)));
+            match key {
+                "theme" => {
+                    let depth = theme.depth;
+                    theme = Theme::built_in(value).ok_or_else(|| error(&format!("unknown theme `{}`", value)))?;
+                    theme.depth = depth;
+                }
+                "colors" => theme.depth = ColorDepth::from_name(value).ok_or_else(|| error("colors is 256 or truecolor"))?,
+                "background" => theme.planes[0] = color()?,
+                "foreground" => theme.planes[1] = color()?,
+                _ => {
+                    let plane = key.strip_prefix("color").and_then(|n| n.parse::<usize>().ok()).filter(|n| *n < 4);
+                    let plane = plane.ok_or_else(|| error(&format!("unknown key `{}`", key)))?;
+                    theme.planes[plane] = color()?;
+                }
+            }
+        }
+        Ok(theme)
+    }
+
+    ///A built-in theme by name, or else a theme file
+    pub fn load(name: &str) -> Result<Theme, String> {
+        if let Some(theme) = Theme::built_in(name) {
+            return Ok(theme);
+        }
+        if !Path::new(name).is_file() {
+            return Err(format!("unknown theme `{}`, use {} or a theme file", name, Theme::BUILT_IN.join(", ")));
+        }
+        let text = fs::read_to_string(name).map_err(|e| format!("cannot read {}: {}", name, e))?;
+        let mut theme = Theme::parse(&text).map_err(|e| format!("{}: {}", name, e))?;
+        theme.name = name.to_owned();
+        Ok(theme)
+    }
+}
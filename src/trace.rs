@@ -0,0 +1,180 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    cpu::Chip8,
+    parser::{OpCode, OpCodeType},
+    session::parse_address,
+    symbols::SymbolTable,
+};
+
+///Machine state an instruction can change, captured before it runs
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct TraceState {
+    registers: [u8; 16],
+    addr_reg: u16,
+    sp: u8,
+    timer: u8,
+    sound_timer: u8,
+}
+
+impl TraceState {
+    pub fn take(cpu: &Chip8) -> Self {
+        let (timer, sound_timer) = cpu.dump_clock();
+        Self {
+            registers: cpu.dump_registers(),
+            addr_reg: cpu.dump_large_register(),
+            sp: cpu.dump_stack().head,
+            timer,
+            sound_timer,
+        }
+    }
+
+    ///Registers that differ in `after`, e.g. `V0=02 I=0300`.
+    ///Timers only count when they did more than the usual countdown
+    pub fn changes(&self, after: &TraceState) -> String {
+        let mut changes = Vec::new();
+        for (reg, (old, new)) in self.registers.iter().zip(after.registers).enumerate() {
+            if *old != new {
+                changes.push(format!("V{:X}={:02X}", reg, new));
+            }
+        }
+        if self.addr_reg != after.addr_reg {
+            changes.push(format!("I={:04X}", after.addr_reg));
+        }
+        if self.sp != after.sp {
+            changes.push(format!("SP={:02X}", after.sp));
+        }
+        if self.timer.saturating_sub(1) != after.timer {
+            changes.push(format!("DT={:02X}", after.timer));
+        }
+        if self.sound_timer.saturating_sub(1) != after.sound_timer {
+            changes.push(format!("ST={:02X}", after.sound_timer));
+        }
+        changes.join(" ")
+    }
+}
+
+///Which instructions end up in the trace, an empty list lets everything through
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct TraceFilter {
+    pub ranges: Vec<(u16, u16)>,    //`end` is exclusive
+    pub types: Vec<OpCodeType>,
+}
+
+impl TraceFilter {
+    ///Parses `<addr>..<end>` or `<addr>+<len>` into a range for `ranges`
+    pub fn parse_range(text: &str, symbols: Option<&SymbolTable>) -> Result<(u16, u16), String> {
+        let (start, end) = if let Some((start, end)) = text.split_once("..") {
+            (parse_address(start, symbols)?, parse_address(end, symbols)?)
+        } else if let Some((start, len)) = text.split_once('+') {
+            let start = parse_address(start, symbols)?;
+            (start, start.saturating_add(parse_address(len, symbols)?))
+        } else {
+            return Err(format!("bad trace range `{}`", text));
+        };
+        if end <= start {
+            return Err(format!("empty trace range `{}`", text));
+        }
+        Ok((start, end))
+    }
+
+    pub fn matches(&self, pc: u16, oc: &OpCode) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|(start, end)| (*start..*end).contains(&pc)))
+            && (self.types.is_empty() || self.types.iter().any(|t| t.same_kind(&oc.oc_type)))
+    }
+}
+
+///Formats one trace line: cycle, PC, op code, mnemonic and the registers it changed
+pub fn format_line(cycle: u64, pc: u16, oc: &OpCode, changes: &str) -> String {
+    let line = format!("{:>8} {:03X} {:04X} {:<16}{}", cycle, pc, oc.op_code, oc.mnemonic(), changes);
+    line.trim_end().to_owned()
+}
+
+///Writes executed instructions to a trace file
+pub struct Tracer {
+    out: Box<dyn Write>,
+    filter: TraceFilter,
+    error: Option<io::Error>,   //First write error, tracing stops after it
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, filter: TraceFilter) -> Self {
+        Self { out, filter, error: None }
+    }
+
+    pub fn create(path: impl AsRef<Path>, filter: TraceFilter) -> io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?)), filter))
+    }
+
+    ///Records the instruction that just ran at `pc`
+    pub fn record(&mut self, cycle: u64, pc: u16, oc: &OpCode, before: &TraceState, cpu: &Chip8) {
+        if self.error.is_some() || !self.filter.matches(pc, oc) {
+            return;
+        }
+        let changes = before.changes(&TraceState::take(cpu));
+        if let Err(err) = writeln!(self.out, "{}", format_line(cycle, pc, oc, &changes)) {
+            self.error = Some(err);
+        }
+    }
+
+    ///Flushes the trace, reporting any error hit while writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+///First line where two traces disagree
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Divergence {
+    pub line: usize,            //1-based
+    pub left: Option<String>,   //`None` when that trace ended first
+    pub right: Option<String>,
+}
+
+impl Divergence {
+    ///Which column differs first
+    pub fn field(&self) -> &'static str {
+        let (Some(left), Some(right)) = (&self.left, &self.right) else {
+            return "length";
+        };
+        let (mut left, mut right) = (left.split_whitespace(), right.split_whitespace());
+        for field in ["cycle", "pc", "op code"] {
+            if left.next() != right.next() {
+                return field;
+            }
+        }
+        "registers"
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "traces diverge at line {} ({} differs)", self.line, self.field())?;
+        writeln!(f, "< {}", self.left.as_deref().unwrap_or("<end of trace>"))?;
+        write!(f, "> {}", self.right.as_deref().unwrap_or("<end of trace>"))
+    }
+}
+
+///Compares two traces line by line, ignoring trailing whitespace
+pub fn first_divergence(left: &str, right: &str) -> Option<Divergence> {
+    let (mut left, mut right) = (left.lines(), right.lines());
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (left.next().map(str::trim_end), right.next().map(str::trim_end)) {
+            (None, None) => return None,
+            (l, r) if l == r => continue,
+            (l, r) => {
+                return Some(Divergence { line, left: l.map(str::to_owned), right: r.map(str::to_owned) });
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+
+use crate::{
+    cpu::{AccessKind, Chip8, MemAccess},
+    session::parse_address,
+    symbols::SymbolTable,
+};
+
+///Register a watchpoint can follow
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WatchReg {
+    V(u8),
+    I,
+}
+
+impl WatchReg {
+    pub fn parse(name: &str) -> Option<WatchReg> {
+        let upper = name.to_ascii_uppercase();
+        if upper == "I" {
+            return Some(WatchReg::I);
+        }
+        let reg = upper.strip_prefix('V').filter(|r| r.len() == 1)?;
+        u8::from_str_radix(reg, 16).ok().map(WatchReg::V)
+    }
+
+    pub fn read(self, cpu: &Chip8) -> u16 {
+        self.value(&cpu.dump_registers(), cpu.dump_large_register())
+    }
+
+    fn value(self, registers: &[u8; 16], addr_reg: u16) -> u16 {
+        match self {
+            WatchReg::V(reg) => registers[reg as usize] as u16,
+            WatchReg::I => addr_reg,
+        }
+    }
+}
+
+impl fmt::Display for WatchReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchReg::V(reg) => write!(f, "V{:X}", reg),
+            WatchReg::I => write!(f, "I"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WatchKind {
+    Memory { start: u16, end: u16, read: bool, write: bool },  //`end` is exclusive
+    Register(WatchReg),
+}
+
+impl WatchKind {
+    ///Parses `<reg>`, `<addr>`, `<addr>..<end>` or `<addr>+<len>`
+    pub fn parse(text: &str, read: bool, write: bool, symbols: Option<&SymbolTable>) -> Result<WatchKind, String> {
+        let text = text.trim();
+        if let Some(reg) = WatchReg::parse(text) {
+            return Ok(WatchKind::Register(reg));
+        }
+        let (start, end) = if let Some((start, end)) = text.split_once("..") {
+            (parse_address(start, symbols)?, parse_address(end, symbols)?)
+        } else if let Some((start, len)) = text.split_once('+') {
+            let start = parse_address(start, symbols)?;
+            (start, start.saturating_add(parse_address(len, symbols)?))
+        } else {
+            let start = parse_address(text, symbols)?;
+            let end = start.checked_add(1).ok_or_else(|| format!("cannot watch past the end of memory at `{}`", text))?;
+            (start, end)
+        };
+        if end <= start {
+            return Err(format!("empty watch range `{}`", text));
+        }
+        Ok(WatchKind::Memory { start, end, read, write })
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Memory { start, end, read, write } => {
+                let mode = match (read, write) {
+                    (true, true) => "rw",
+                    (true, false) => "r",
+                    _ => "w",
+                };
+                write!(f, "{} [{:#05X}..{:#05X})", mode, start, end)
+            }
+            WatchKind::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+///What tripped a watchpoint
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WatchEvent {
+    Memory(MemAccess),
+    Register { reg: WatchReg, old: u16, new: u16 },
+}
+
+///A triggered watchpoint together with the instruction that caused it
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WatchHit {
+    pub id: u32,
+    pub pc: u16,
+    pub op_code: u16,
+    pub event: WatchEvent,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "watchpoint w{}: ", self.id)?;
+        match self.event {
+            WatchEvent::Memory(access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                write!(f, "{} {:#05X} {:#04X} -> {:#04X}", kind, access.addr, access.old, access.new)
+            }
+            WatchEvent::Register { reg, old, new } => write!(f, "{} {:#X} -> {:#X}", reg, old, new),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub kind: WatchKind,
+    pub hits: u32,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "w{} {} hits={}", self.id, self.kind, self.hits)
+    }
+}
+
+///Register values captured before an instruction so changes can be detected afterwards
+#[derive(Debug, Clone, Copy)]
+pub struct RegSnapshot {
+    registers: [u8; 16],
+    addr_reg: u16,
+}
+
+impl RegSnapshot {
+    pub fn take(cpu: &Chip8) -> Self {
+        Self {
+            registers: cpu.dump_registers(),
+            addr_reg: cpu.dump_large_register(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WatchpointSet {
+    list: Vec<Watchpoint>,
+    next_id: u32,
+}
+
+impl WatchpointSet {
+    pub fn add(&mut self, kind: WatchKind) -> u32 {
+        self.next_id += 1;
+        self.list.push(Watchpoint { id: self.next_id, kind, hits: 0 });
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|w| w.id != id);
+        self.list.len() != len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    ///Checks the instruction that just ran at `pc` against every watchpoint
+    pub fn check(&mut self, pc: u16, op_code: u16, before: &RegSnapshot, cpu: &Chip8) -> Option<WatchHit> {
+        let mut hit = None;
+        let (registers, addr_reg) = (cpu.dump_registers(), cpu.dump_large_register());
+        for wp in self.list.iter_mut() {
+            let event = match wp.kind {
+                WatchKind::Memory { start, end, read, write } => cpu
+                    .last_accesses()
+                    .iter()
+                    .find(|a| {
+                        (start..end).contains(&a.addr)
+                            && match a.kind {
+                                AccessKind::Read => read,
+                                AccessKind::Write => write,
+                            }
+                    })
+                    .map(|a| WatchEvent::Memory(*a)),
+                WatchKind::Register(reg) => {
+                    let old = reg.value(&before.registers, before.addr_reg);
+                    let new = reg.value(&registers, addr_reg);
+                    (old != new).then_some(WatchEvent::Register { reg, old, new })
+                }
+            };
+            if let Some(event) = event {
+                wp.hits += 1;
+                hit.get_or_insert(WatchHit { id: wp.id, pc, op_code, event });
+            }
+        }
+        hit
+    }
+}
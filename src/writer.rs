@@ -0,0 +1,91 @@
+use std::{fs, io, path::Path};
+
+use crate::{parser::OpCode, symbols::SymbolTable};
+
+///Formatting details of a hex listing, kept so a loaded file can be written back unchanged
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct HexStyle {
+    pub lowercase: bool,
+    pub crlf: bool,
+    pub trailing_newline: bool,
+}
+
+impl HexStyle {
+    ///Picks up the style of an existing listing
+    pub fn detect(text: &str) -> Self {
+        Self {
+            lowercase: text.chars().any(|c| matches!(c, 'a'..='f')),
+            crlf: text.contains("\r\n"),
+            trailing_newline: text.ends_with('\n'),
+        }
+    }
+}
+
+///Raw `.ch8` bytes, one big-endian word per op code
+pub fn to_binary(program: &[OpCode]) -> Vec<u8> {
+    program.iter().flat_map(|oc| oc.op_code.to_be_bytes()).collect()
+}
+
+///Line-per-word hex text as read by `parser::parse_text`
+pub fn to_text(program: &[OpCode], style: HexStyle) -> String {
+    let newline = if style.crlf { "\r\n" } else { "\n" };
+    let words: Vec<String> = program
+        .iter()
+        .map(|oc| {
+            if style.lowercase {
+                format!("{:04x}", oc.op_code)
+            } else {
+                format!("{:04X}", oc.op_code)
+            }
+        })
+        .collect();
+    let mut text = words.join(newline);
+    if style.trailing_newline && !program.is_empty() {
+        text.push_str(newline);
+    }
+    text
+}
+
+///Human readable listing with addresses, disassembly and symbols
+pub fn to_listing(program: &[OpCode], symbols: Option<&SymbolTable>) -> String {
+    let mut out = String::new();
+    for (addr, oc) in program.iter().enumerate() {
+        let addr = addr as u16;
+        if let Some(symbols) = symbols {
+            if let Some((label, 0)) = symbols.label(addr) {
+                out.push_str(&format!("{}:\n", label));
+            }
+        }
+        out.push_str(&listing_line(addr, oc, symbols));
+        out.push('\n');
+    }
+    out
+}
+
+///One instruction of a listing, without the newline
+pub fn listing_line(addr: u16, oc: &OpCode, symbols: Option<&SymbolTable>) -> String {
+    let mut line = format!("{:#05X}: {:04X}  {}", addr, oc.op_code, oc.mnemonic());
+    if let Some(loc) = symbols.and_then(|s| s.source(addr)) {
+        while line.len() < 32 {
+            line.push(' ');
+        }
+        line.push_str(&format!("; {}:{}", loc.file, loc.line));
+    }
+    line
+}
+
+///Writes `program` in the format implied by the extension: `.ch8` raw, `.lst` listing, hex text in
+///`style` otherwise
+pub fn save<P: AsRef<Path>>(path: P, program: &[OpCode], symbols: Option<&SymbolTable>, style: HexStyle) -> io::Result<()> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("ch8") => fs::write(path, to_binary(program)),
+        Some("lst") => fs::write(path, to_listing(program, symbols)),
+        _ => fs::write(path, to_text(program, style)),
+    }
+}
+
+///Writes a memory image byte for byte
+pub fn save_memory<P: AsRef<Path>>(path: P, memory: &[u8]) -> io::Result<()> {
+    fs::write(path, memory)
+}